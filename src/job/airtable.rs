@@ -2,112 +2,246 @@ use std::time::Instant;
 use polars::lazy::dsl::{col, lit, Expr};
 use polars::prelude::*;
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::prelude::*;
-use std::time::Duration;
-use tokio::time::timeout;
-use tracing::{error, info};
-
-use super::job::RestApi;
-use super::utility;
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, SeekFrom};
+use tracing::{info, warn};
+
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use std::pin::pin;
+
+use super::checkpoint::Cursor;
+use super::config::{at_filtered_columns, SourceConfig};
+use super::error::JobError;
+use super::job::{join_units, RestApi, RunContext, Source};
+use super::manifest::ColumnSchema;
+use super::output::OutputFormat;
+use super::pagination::token;
+use super::plan::PlanEntry;
+use super::registry::UnitFilters;
+use super::summary::{UnitReport, UnitStats};
 use super::{
-    config::get_config,
+    config::AirtableConfig,
     job::{Airtable, AtJobDetail},
 };
-use crate::job::job::{AwsS3, StoragePlatform, Tasks};
+use crate::job::job::Tasks;
+
+impl Airtable {
+    /// Filters the units of work can be narrowed down by.
+    pub const FILTERS: &'static [&'static str] = &["endpoint", "year"];
+
+    pub fn build(
+        name: &str,
+        config: &SourceConfig,
+        context: RunContext,
+        filters: &UnitFilters,
+    ) -> Result<Box<dyn Source>, JobError> {
+        Ok(Box::new(Airtable {
+            context,
+            name: name.to_string(),
+            settings: config.settings(name)?,
+            job_details: AtJobDetail::new(),
+            endpoint_filter: filters.get("endpoint").cloned(),
+            year_filter: filters.get("year").cloned(),
+        }))
+    }
 
-impl Tasks for Airtable {
-    #[tracing::instrument(err,skip_all)]
-    async fn extraction(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        
+    /// Every enabled base x table unit of work declared in `bases`, narrowed down by the
+    /// endpoint/year filters.
+    pub fn job_matrix(&self) -> Vec<AtJobDetail> {
+        let mut job_details = Vec::new();
 
-        let mut offset_clone = "".to_string();
-        let start_time = Instant::now();
-        let mut file = File::create(format!(
-            "{}_output_{}.json",
-            self.job_details.airtable_endpoint, self.job_details.year
-        ))?;
+        for base in self.settings.bases.iter().filter(|base| base.enabled) {
+            for table in base.tables.iter().filter(|table| table.enabled) {
+                let details = AtJobDetail {
+                    airtables_type: table.airtables_type.clone(),
+                    airtable_endpoint: table.endpoint.clone(),
+                    year: base.year.clone(),
+                    api_endpoint: table.api_endpoint.clone().unwrap_or_default(),
+                    airtable_url: base.url.clone(),
+                    auth_token: base.auth_token.clone().unwrap_or_default(),
+                    offset_value: "".to_string(),
+                    rate_limit: None,
+                };
+                job_details.push(details);
+            }
+        }
 
-        loop {
+        job_details.retain(|job| {
+            self.endpoint_filter
+                .as_ref()
+                .is_none_or(|endpoint| endpoint == &job.airtable_endpoint)
+                && self.year_filter.as_ref().is_none_or(|year| year == &job.year)
+        });
+
+        job_details
+    }
+}
+
+impl Airtable {
+    pub fn output_format(&self, job: &AtJobDetail) -> OutputFormat {
+        self.context.output_format(self.source(), &job.airtable_endpoint)
+    }
+}
 
-            info!("extracting data from airtabele");
-            self.job_details.offset_value = offset_clone;
-            let response = self.job_details.fetch_sync().await?;
+impl AtJobDetail {
+    /// Copy of the job with the api endpoint and token that were not overridden on the base
+    /// resolved from the airtable config, along with the rate limit of its base.
+    pub fn resolve(&self, config: &AirtableConfig) -> Result<AtJobDetail, JobError> {
+        let mut job_details = self.clone();
+
+        if job_details.api_endpoint.is_empty() {
+            job_details.api_endpoint = config
+                .api_endpoints
+                .get(&self.airtables_type)
+                .and_then(|endpoints| endpoints.get(&self.airtable_endpoint))
+                .ok_or(JobError::Config(format!(
+                    "no api endpoint for airtable {} in airtable.api_endpoints.{}",
+                    self.airtable_endpoint, self.airtables_type
+                )))?
+                .clone();
+        }
+        if job_details.auth_token.is_empty() {
+            job_details.auth_token = config.auth_token.clone();
+        }
+        job_details.rate_limit = config.rate_limit.clone();
 
-            let data = response.get("records").unwrap().as_array().unwrap();
+        Ok(job_details)
+    }
 
-            for d in data {
-                serde_json::to_writer(&mut file, &d)?;
-                match writeln!(&mut file) {
-                    Ok(()) => continue,
-                    Err(err) => error!(
-                        "error write to json for {}\nMessage: {:?}",
-                        self.job_details.api_endpoint, err
-                    ),
-                }
-            }
+    /// Key of the unit of work, e.g. `order_sheet/2023`.
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.airtable_endpoint, self.year)
+    }
+
+    pub fn output_file(&self) -> String {
+        format!("{}_output_{}.json", self.airtable_endpoint, self.year)
+    }
 
+    pub fn result_file(&self, format: OutputFormat) -> String {
+        format!(
+            "result_{}_{}.{}",
+            self.airtable_endpoint,
+            self.year,
+            format.extension()
+        )
+    }
+}
 
+impl Tasks for Airtable {
+    #[tracing::instrument(err,skip_all)]
+    async fn extraction(&mut self) -> Result<usize, JobError> {
+        let mut records = 0;
+        let mut pages = 0;
+        let start_time = Instant::now();
+        let unit_id = self.context.unit_id(self.source(), &self.job_details.key());
+        let output_path = self
+            .context
+            .output_path(self.source(), &self.job_details.output_file())?;
+
+        let mut resuming = false;
+        self.job_details.offset_value = "".to_string();
+        let mut file = match self.context.checkpoints.cursor(&unit_id) {
+            Some(cursor) => match resume_file(&output_path, cursor.file_len) {
+                Ok(file) => {
+                    info!("resuming {} at offset {}", unit_id, cursor.offset);
+                    resuming = true;
+                    self.job_details.offset_value = cursor.offset;
+                    records = cursor.records;
+                    file
+                }
+                Err(err) => {
+                    warn!("cannot resume {} from {}: {}", unit_id, output_path, err);
+                    File::create(&output_path)?
+                }
+            },
+            None => File::create(&output_path)?,
+        };
 
-            offset_clone = match response.get("offset").cloned() {
-                Some(row) => row.as_str().unwrap().to_string(),
-                None => "None".to_string(),
-            };
+        let http = self.context.http.clone();
+        loop {
+            let job_details = self.job_details.clone();
+            let mut stream = pin!(job_details.pages(&http));
+            let mut restart = false;
+
+            while let Some(page) = stream.next().await {
+                let page = match page {
+                    // airtable offsets expire, start the table over when a stored one is refused
+                    Err(JobError::Client { status: 422, body }) if resuming => {
+                        warn!("airtable refused the stored offset of {}: {}", unit_id, body);
+                        self.context.checkpoints.clear_cursor(&unit_id)?;
+                        file.set_len(0)?;
+                        file.seek(SeekFrom::Start(0))?;
+                        records = 0;
+                        restart = true;
+                        break;
+                    }
+                    page => page?,
+                };
+                resuming = false;
+
+                let data = job_details.records(&page.body)?;
+                for d in data {
+                    serde_json::to_writer(&mut file, &d)?;
+                    writeln!(&mut file)?;
+                }
+                records += data.len();
+                pages = page.number;
+
+                if let Some(offset) = token(&page.body, "offset") {
+                    self.context.checkpoints.save_cursor(
+                        &unit_id,
+                        Cursor {
+                            offset,
+                            file_len: file.stream_position()?,
+                            records,
+                        },
+                    )?;
+                }
+            }
 
-            if offset_clone == "None" {
+            if !restart {
                 break;
             }
+            resuming = false;
+            self.job_details.offset_value = "".to_string();
         }
 
         let end_time = Instant::now();
         let duration = end_time - start_time;
         let duration_minutes = duration.as_secs() as f64 / 60.0;
 
-        info!("Done extracting All data from airtable in {:.2} minutes",duration_minutes);
+        info!(
+            "Done extracting {} records from airtable in {} page(s) in {:.2} minutes",
+            records, pages, duration_minutes
+        );
 
-        Ok(())
+        Ok(records)
     }
 
     #[tracing::instrument(err,skip_all)]
-    async fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let config = get_config().await?;
-
-        let airtable_url: &str = &config[format!("url_{}", self.job_details.year)]
-            .as_str()
-            .ok_or("invalid url")?;
-        let job_config_path: &str = &config["job_config_path"].as_str().ok_or("invalid_path")?;
-        let api_endpoint = config[format!("api_endpoint_{}", self.job_details.airtables_type)]
-            [format!("{}", self.job_details.airtable_endpoint)]
-        .as_str()
-        .ok_or("endpoint not valid")?
-        .to_string();
-        let auth_token: &str = &config["auth_token"].as_str().ok_or("invalid auth token")?;
-        let columns = crate::job::config::at_filtered_columns(
-            format!("{}", self.job_details.airtable_endpoint).as_str(),
+    async fn execute(&mut self) -> Result<UnitStats, JobError> {
+        let job_config_path: &str = &self.context.config.paths.airtable_columns;
+        let columns = at_filtered_columns(
+            self.job_details.airtable_endpoint.as_str(),
             "airtable",
-            format!("{}", self.job_details.year).as_str(),
+            self.job_details.year.as_str(),
             job_config_path,
         )?;
         let mut map = columns.clone();
 
-        let mut job_details_clone = self.job_details.clone();
-        job_details_clone.api_endpoint = api_endpoint;
-        job_details_clone.airtable_url = airtable_url.to_string();
-        job_details_clone.auth_token = auth_token.to_string();
-        self.job_details = job_details_clone;
+        self.job_details = self.job_details.resolve(&self.settings)?;
 
         self.extraction().await?;
 
-        let mut file = std::fs::File::open(format!(
-            "{}_output_{}.json",
-            self.job_details.airtable_endpoint, self.job_details.year
-        ))?;
+        let mut file =
+            std::fs::File::open(self.context.output_path(self.source(), &self.job_details.output_file())?)?;
         let df: DataFrame = JsonLineReader::new(&mut file)
             .finish()?
             .unnest(["fields"])?;
 
         let polars_columns: HashSet<&str> =
-            df.get_columns().into_iter().map(|s| s.name()).collect();
+            df.get_columns().iter().map(|s| s.name()).collect();
 
         let columns_from_config: HashSet<&str> =
             columns.keys().map(|column| column.as_str()).collect();
@@ -116,7 +250,9 @@ impl Tasks for Airtable {
         let difference: Vec<&str> = temp_difference.into_iter().cloned().collect();
 
         difference.iter().for_each(|f| {
-            utility::update_nested_value(&mut map, f, "type", serde_json::json!("str"));
+            if let Some(mapping) = map.get_mut(*f) {
+                mapping.data_type = "str".to_string();
+            }
         });
 
         let empty_column: Vec<Expr> = difference
@@ -138,10 +274,9 @@ impl Tasks for Airtable {
             .select(selected_columns);
 
         map.iter().for_each(|(old, new)| {
-            let name = new.get("new").unwrap().as_str().unwrap();
-            let data_type = new.get("type").unwrap().as_str().unwrap();
+            let name = new.new.as_str();
 
-            if data_type == "list" {
+            if new.data_type == "list" {
                 new_columns.push(col(old).list().get(lit(0)).alias(name))
             } else {
                 new_columns.push(col(old).alias(name))
@@ -151,95 +286,160 @@ impl Tasks for Airtable {
 
         let final_df = new_df.with_columns(new_columns).select(final_columns);
 
-        let file_name = format!(
-            "result_{}_{}.csv",
-            self.job_details.airtable_endpoint, self.job_details.year
-        );
-        let mut file = std::fs::File::create(&file_name)?;
-
-        match CsvWriter::new(&mut file).finish(&mut final_df.collect()?) {
-            Ok(_) => {
-                info!("success write table {} with year {}", self.job_details.airtable_endpoint,self.job_details.year);
-                StoragePlatform::upload(
-                    AwsS3 {
-                        config: None,
-                        client: None,
-                        bucket_name: None,
-                    },
-                    file_name,
-                )
-                .await?
-            }
-            Err(e) => error!(
-                "error writing table {}\nMessage: {:?}",
-                self.job_details.airtable_endpoint, e
-            ),
-        }
-
-        Ok(())
+        let format = self.output_format(&self.job_details);
+        let result_file = self.job_details.result_file(format);
+        let file_name = self.context.output_path(self.source(), &result_file)?;
+        let mut final_df = final_df.collect()?;
+
+        format.write(&mut final_df, &file_name, self.context.config.output.compression)?;
+        info!("success write table {} with year {}", self.job_details.airtable_endpoint,self.job_details.year);
+
+        let object = self.context.output_object(
+            self.source(),
+            &self.job_details.airtable_endpoint,
+            &result_file,
+            format,
+        )
+        .await?;
+        let mut stats = UnitStats::for_object(
+            &object,
+            final_df.height(),
+            ColumnSchema::of_frame(&final_df),
+        )?;
+        stats.destinations = self.context.upload(self.source(), &self.job_details.airtable_endpoint, &object).await?;
+        Ok(stats)
     }
 
-    #[tracing::instrument(err)]
-    async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let years = vec![
-            //"2024".to_string(), 
-            "2023".to_string(),
-            //"2022".to_string()
-            ];
-
-        let airtables_types = vec![
-            "product".to_string(), 
-            "launch".to_string()
-        ];
-
-        let endpoints = vec![
-            ("launch", vec!["order_sheet"]),
-            ("product", vec!["child_product"]),
-        ];
+    fn source(&self) -> &str {
+        &self.name
+    }
+}
 
-        let mut job_details = Vec::new();
+impl Airtable {
+    #[tracing::instrument(err, skip_all, fields(source = %self.name))]
+    async fn run_units(&self) -> Result<Vec<UnitReport>, JobError> {
+        let job_details = self.job_matrix();
 
-        for year in &years {
-            for airtables_type in &airtables_types {
-                for endpoint in &endpoints {
-                    if endpoint.0 == airtables_type {
-                        for endpoint_name in &endpoint.1 {
-                            let details = AtJobDetail {
-                                airtables_type: airtables_type.clone(),
-                                airtable_endpoint: endpoint_name.to_string(),
-                                year: year.clone(),
-                                api_endpoint: "".to_string(),
-                                airtable_url: "".to_string(),
-                                auth_token: "".to_string(),
-                                offset_value: "".to_string(),
-                            };
-                            job_details.push(details);
-                        }
-                    }
-                }
-            }
+        if job_details.is_empty() {
+            return Err(JobError::Config(
+                "no airtable job matches the given endpoint/year filter".to_string(),
+            ));
         }
 
         let handles: Vec<_> = job_details
             .into_iter()
             .map(|job| {
+                let key = job.key();
+                let format = self.output_format(&job);
+                let mut partial_files =
+                    vec![self.context.local_path(self.source(), &job.output_file())];
+                partial_files.extend(self.context.result_paths(
+                    self.source(),
+                    &job.result_file(format),
+                    format,
+                ));
                 let mut airtable_clone = self.clone();
                 airtable_clone.job_details = job;
-                tokio::spawn(async move {
-                    if let Err(err) =
-                        timeout(Duration::from_secs(3600), airtable_clone.execute()).await
-                    {
-                        eprintln!("Error executing task: {}", err);
-                    }
-                })
+                let handle = self.context.spawn_unit(
+                    self.source(),
+                    &key,
+                    partial_files,
+                    async move { airtable_clone.execute().await },
+                );
+                (key, handle)
             })
             .collect();
 
-        // Wait for all tasks to complete
-        for handle in handles {
-            handle.await.expect("Failed to join task");
-        }
+        Ok(join_units(&self.context, self.source(), handles).await)
+    }
+
+    #[tracing::instrument(err, skip_all)]
+    async fn plan_units(&self) -> Result<Vec<PlanEntry>, JobError> {
+        self.job_matrix()
+            .iter()
+            .map(|job| {
+                let job = job.resolve(&self.settings)?;
+                Ok(PlanEntry {
+                    execution_date: self.context.execution_date.clone(),
+                    source: self.source().to_string(),
+                    key: job.key(),
+                    request: job.first_page()?.to_string(),
+                    s3_key: {
+                        let format = self.output_format(&job);
+                        self.context.upload_key(
+                            self.source(),
+                            &job.airtable_endpoint,
+                            &job.result_file(format),
+                            format,
+                        )
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+impl Source for Airtable {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<Vec<UnitReport>, JobError>> {
+        Box::pin(self.run_units())
+    }
+
+    fn plan(&self) -> BoxFuture<'_, Result<Vec<PlanEntry>, JobError>> {
+        Box::pin(self.plan_units())
+    }
+
+    fn validate(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut problems = Vec::new();
+
+            for base in self.settings.bases.iter().filter(|base| base.enabled) {
+                for table in base.tables.iter().filter(|table| table.enabled) {
+                    if let Err(err) = at_filtered_columns(
+                        &table.endpoint,
+                        "airtable",
+                        &base.year,
+                        &self.context.config.paths.airtable_columns,
+                    ) {
+                        problems.push(err.to_string());
+                    }
+
+                    let declared = self
+                        .settings
+                        .api_endpoints
+                        .get(&table.airtables_type)
+                        .is_some_and(|endpoints| endpoints.contains_key(&table.endpoint));
+                    if table.api_endpoint.is_none() && !declared {
+                        problems.push(format!(
+                            "{} table {}/{} has no api_endpoint in {}.api_endpoints.{}",
+                            self.name, table.endpoint, base.year, self.name, table.airtables_type
+                        ));
+                    }
+                }
+            }
+
+            problems
+        })
+    }
+
+    fn filtered(&self) -> bool {
+        self.endpoint_filter.is_some() || self.year_filter.is_some()
+    }
+}
 
-        Ok(())
+/// Output file of an interrupted extraction, cut back to the last checkpointed page.
+fn resume_file(path: &str, file_len: u64) -> Result<File, JobError> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    if file.metadata()?.len() < file_len {
+        return Err(JobError::Storage(format!(
+            "{} is shorter than its checkpoint",
+            path
+        )));
     }
+    file.set_len(file_len)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
 }
@@ -1,52 +1,66 @@
-use super::job::{AtJobDetail, Impact, RestApi};
+use super::config::{Pagination, RateLimit, RestAuth};
+use super::error::JobError;
+use super::http::HttpClient;
+use super::job::{AtJobDetail, Impact, Rest, RestApi};
+use super::pagination::set_param;
+use super::utility;
 use chrono::Datelike;
 use chrono::Duration;
 use chrono::NaiveDate;
-use std::{str::FromStr, time::Duration as DurationStd};
+use reqwest::{RequestBuilder, Url};
+use serde_json::Value;
+use std::str::FromStr;
 
 impl RestApi for AtJobDetail {
-    async fn fetch_sync(&mut self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        let url = format!(
-            "{}/{}/?pageSize=100&offset={}",
-            &self.airtable_url, &self.api_endpoint, &self.offset_value,
-        );
-
-        let client = reqwest::Client::new();
-        let resp = client
-            .get(&url)
-            .header("Authorization", &self.auth_token)
-            .timeout(DurationStd::from_secs(300))
-            .send()
-            .await?;
-
-        let json: serde_json::Value = resp.json().await?;
-        Ok(json)
+    fn first_page(&self) -> Result<Url, JobError> {
+        let mut url = parse_url(&format!("{}/{}/", self.airtable_url, self.api_endpoint))?;
+        set_param(&mut url, "pageSize", "100");
+        if !self.offset_value.is_empty() {
+            set_param(&mut url, "offset", &self.offset_value);
+        }
+        Ok(url)
+    }
+
+    fn request(&self, http: &HttpClient, url: &Url) -> RequestBuilder {
+        http.get(url.as_str()).header("Authorization", &self.auth_token)
+    }
+
+    fn rate_limit(&self) -> (String, Option<&RateLimit>) {
+        (self.airtable_url.clone(), self.rate_limit.as_ref())
+    }
+
+    fn pagination(&self) -> Pagination {
+        Pagination::Cursor {
+            cursor: "offset".to_string(),
+            param: "offset".to_string(),
+        }
+    }
+
+    fn records<'a>(&self, body: &'a Value) -> Result<&'a Vec<Value>, JobError> {
+        body.get("records")
+            .and_then(|records| records.as_array())
+            .ok_or_else(|| JobError::Parse(format!("airtable response has no records: {}", body)))
     }
 }
 
-impl RestApi for Impact {
-    async fn fetch_sync(&mut self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+impl Impact {
+    /// Report url for the execution date, with the lookback window already applied.
+    pub fn report_url(&self) -> Result<String, JobError> {
         let load_date = NaiveDate::from_str(&self.execution_date)?;
         let tdy_month = load_date.month();
         let tmr_month = (load_date + Duration::days(1)).month();
-        let client = reqwest::Client::new();
-        let mut url: String = String::new();
 
-        let window_start_date: NaiveDate;
-
-        if tdy_month != tmr_month {
+        let window_start_date = if tdy_month != tmr_month {
             let date_90_days_ago = load_date - Duration::days(90);
-            window_start_date =
-                NaiveDate::from_ymd_opt(date_90_days_ago.year(), date_90_days_ago.month(), 1)
-                    .unwrap();
+            NaiveDate::from_ymd_opt(date_90_days_ago.year(), date_90_days_ago.month(), 1).unwrap()
         } else {
-            window_start_date = NaiveDate::from_str(&self.execution_date)? - Duration::days(3);
-        }
+            NaiveDate::from_str(&self.execution_date)? - Duration::days(3)
+        };
 
         let window_start_date_str = window_start_date.to_string();
         let start_date = window_start_date_str.as_str();
 
-        url = if self.report == "partner_funds_transfer_listing_" {
+        let url = if self.report == "partner_funds_transfer_listing_" {
             format!(
                 "{}{}.json?year_no_all_fin={}{}",
                 self.api_url,
@@ -61,13 +75,98 @@ impl RestApi for Impact {
             )
         };
 
-        let resp = client
-            .get(&url)
+        Ok(url)
+    }
+}
+
+impl RestApi for Impact {
+    fn first_page(&self) -> Result<Url, JobError> {
+        parse_url(&self.report_url()?)
+    }
+
+    fn request(&self, http: &HttpClient, url: &Url) -> RequestBuilder {
+        http.get(url.as_str())
             .basic_auth(&self.auth_sid, Some(&self.auth_token))
-            .send()
-            .await?;
+    }
 
-        let json: serde_json::Value = resp.json().await?;
-        Ok(json)
+    fn rate_limit(&self) -> (String, Option<&RateLimit>) {
+        (
+            format!("impact/{}", self.auth_sid),
+            self.settings.rate_limit.as_ref(),
+        )
     }
+
+    /// Report pages link to the next one until the last, whose `@nextpageuri` is empty.
+    fn pagination(&self) -> Pagination {
+        Pagination::NextUrl {
+            next: "@nextpageuri".to_string(),
+        }
+    }
+
+    fn records<'a>(&self, body: &'a Value) -> Result<&'a Vec<Value>, JobError> {
+        body.get("Records")
+            .and_then(|records| records.as_array())
+            .ok_or_else(|| JobError::Parse(format!("impact response has no Records: {}", body)))
+    }
+}
+
+impl RestApi for Rest {
+    fn first_page(&self) -> Result<Url, JobError> {
+        self.table_url(self.table()?)
+    }
+
+    fn request(&self, http: &HttpClient, url: &Url) -> RequestBuilder {
+        let mut request = http.get(url.as_str());
+        let table_headers = self.table().map(|table| &table.headers).into_iter().flatten();
+        for (name, value) in self.settings.headers.iter().chain(table_headers) {
+            request = request.header(name, value);
+        }
+        match &self.settings.auth {
+            Some(RestAuth::Bearer { token }) => request.bearer_auth(token),
+            Some(RestAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(RestAuth::Header { name, value }) => request.header(name, value),
+            // a next page url of the api may carry the parameter already
+            Some(RestAuth::Query { name, value })
+                if !url.query_pairs().any(|(key, _)| key == *name) =>
+            {
+                request.query(&[(name, value)])
+            }
+            Some(RestAuth::Query { .. }) => request,
+            None => request,
+        }
+    }
+
+    fn rate_limit(&self) -> (String, Option<&RateLimit>) {
+        (format!("rest/{}", self.name), self.settings.rate_limit.as_ref())
+    }
+
+    fn pagination(&self) -> Pagination {
+        self.table()
+            .map(|table| table.pagination.clone())
+            .unwrap_or_default()
+    }
+
+    fn records<'a>(&self, body: &'a Value) -> Result<&'a Vec<Value>, JobError> {
+        let table = self.table()?;
+        match &table.records {
+            Some(path) => utility::json_path(body, path),
+            None => Some(body),
+        }
+        .and_then(|records| records.as_array())
+        .ok_or_else(|| {
+            JobError::Parse(format!(
+                "{}/{} page has no records array at {}: {}",
+                self.name,
+                table.name,
+                table.records.as_deref().unwrap_or("its root"),
+                body
+            ))
+        })
+    }
+}
+
+fn parse_url(url: &str) -> Result<Url, JobError> {
+    Url::parse(url).map_err(|err| JobError::Config(format!("invalid url {}: {}", url, err)))
 }
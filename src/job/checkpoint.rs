@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::error::JobError;
+use super::summary::UnitStats;
+
+/// Progress of a run, persisted as `{dir}/{run_id}.json` after every change so that
+/// `run --resume <run id>` can run the same units again, skip the ones it completed and
+/// continue the pagination of the ones it did not.
+#[derive(Clone, Debug)]
+pub struct CheckpointStore {
+    /// `None` keeps the checkpoints in memory only, e.g. for `plan`.
+    path: Option<String>,
+    state: Arc<Mutex<Checkpoint>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Checkpoint {
+    run_id: String,
+    params: RunParams,
+    units: BTreeMap<String, UnitCheckpoint>,
+}
+
+/// What a run extracts, as given on the command line of its first attempt.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RunParams {
+    pub execution_dates: Vec<String>,
+    /// Selected source instances, empty for every instance.
+    pub sources: Vec<String>,
+    pub filters: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct UnitCheckpoint {
+    completed: Option<UnitStats>,
+    cursor: Option<Cursor>,
+}
+
+/// Where the pagination of an in-flight unit stopped, `file_len` is the size of its raw
+/// output file once the page before `offset` was written.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cursor {
+    pub offset: String,
+    pub file_len: u64,
+    pub records: usize,
+}
+
+impl CheckpointStore {
+    pub fn create(dir: &str, run_id: &str, params: RunParams) -> Result<Self, JobError> {
+        std::fs::create_dir_all(dir)?;
+        let store = CheckpointStore {
+            path: Some(checkpoint_path(dir, run_id)),
+            state: Arc::new(Mutex::new(Checkpoint {
+                run_id: run_id.to_string(),
+                params,
+                units: BTreeMap::new(),
+            })),
+        };
+        store.update(|_| ())?;
+        Ok(store)
+    }
+
+    /// Checkpoints of an earlier run to resume.
+    pub fn open(dir: &str, run_id: &str) -> Result<Self, JobError> {
+        let path = checkpoint_path(dir, run_id);
+        if !Path::new(&path).is_file() {
+            return Err(JobError::Config(format!(
+                "no checkpoint for run {} in {}",
+                run_id, dir
+            )));
+        }
+        let checkpoint: Checkpoint = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+
+        Ok(CheckpointStore {
+            path: Some(path),
+            state: Arc::new(Mutex::new(checkpoint)),
+        })
+    }
+
+    pub fn in_memory(run_id: &str) -> Self {
+        CheckpointStore {
+            path: None,
+            state: Arc::new(Mutex::new(Checkpoint {
+                run_id: run_id.to_string(),
+                ..Checkpoint::default()
+            })),
+        }
+    }
+
+    /// Parameters the run was started with.
+    pub fn params(&self) -> RunParams {
+        self.lock().params.clone()
+    }
+
+    /// Stats of `unit` if an earlier attempt of this run completed it.
+    pub fn completed(&self, unit: &str) -> Option<UnitStats> {
+        self.lock().units.get(unit).and_then(|unit| unit.completed.clone())
+    }
+
+    pub fn cursor(&self, unit: &str) -> Option<Cursor> {
+        self.lock().units.get(unit).and_then(|unit| unit.cursor.clone())
+    }
+
+    pub fn save_cursor(&self, unit: &str, cursor: Cursor) -> Result<(), JobError> {
+        self.update(|checkpoint| {
+            checkpoint.units.entry(unit.to_string()).or_default().cursor = Some(cursor);
+        })
+    }
+
+    pub fn clear_cursor(&self, unit: &str) -> Result<(), JobError> {
+        self.update(|checkpoint| {
+            if let Some(unit) = checkpoint.units.get_mut(unit) {
+                unit.cursor = None;
+            }
+        })
+    }
+
+    pub fn complete(&self, unit: &str, stats: &UnitStats) -> Result<(), JobError> {
+        self.update(|checkpoint| {
+            checkpoint.units.insert(
+                unit.to_string(),
+                UnitCheckpoint {
+                    completed: Some(stats.clone()),
+                    cursor: None,
+                },
+            );
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Checkpoint> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Applies `change` and rewrites the checkpoint file, through a temporary file so an
+    /// interrupted write never leaves a truncated checkpoint behind.
+    fn update(&self, change: impl FnOnce(&mut Checkpoint)) -> Result<(), JobError> {
+        let mut checkpoint = self.lock();
+        change(&mut checkpoint);
+
+        if let Some(path) = &self.path {
+            let temp_path = format!("{}.tmp", path);
+            std::fs::write(&temp_path, serde_json::to_vec_pretty(&*checkpoint)?)?;
+            std::fs::rename(&temp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+fn checkpoint_path(dir: &str, run_id: &str) -> String {
+    format!("{}/{}.json", dir, run_id)
+}
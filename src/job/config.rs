@@ -1,119 +1,1152 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{self, Value};
-use std::collections::HashMap;
-use std::env;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self};
-use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use super::error::JobError;
+use super::output::{Compression, OutputFormat};
+
+/// What an airtable column is renamed to and how it is read.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ColumnMapping {
+    pub new: String,
+    /// `list` keeps the first item of a list column, any other type the column as is.
+    #[serde(rename = "type")]
+    pub data_type: String,
+}
 
 pub fn at_filtered_columns(
     table: &str,
     job_name: &str,
     year: &str,
     config_path: &str,
-) -> serde_json::Result<HashMap<String, Value>> {
+) -> Result<HashMap<String, ColumnMapping>, JobError> {
     let file_path = format!("{}/{}/{}/{}.json", config_path, job_name, year, table);
 
-    let mut file_content = String::new();
-    let mut file = File::open(file_path).expect("Failed to open file");
-    file.read_to_string(&mut file_content)
-        .expect("Failed to read file");
+    let file_content = read_file(&file_path)?;
 
-    let map: HashMap<String, Value> = serde_json::from_str(&file_content).unwrap();
+    let map: HashMap<String, ColumnMapping> =
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&file_content))
+            .map_err(|err| {
+                let entry = err.path().to_string();
+                JobError::Config(format!(
+                    "invalid column mapping {} at entry {}: {}",
+                    file_path,
+                    entry,
+                    err.into_inner()
+                ))
+            })?;
 
     Ok(map)
 }
 
+/// Typed view of the pipeline yaml, loaded once at startup.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Bucket of the `s3` backend, only needed when a destination lists it.
+    pub aws: Option<AwsConfig>,
+    /// Single instances of the built in source types, named after their type. Parsed with
+    /// the file, so their errors keep the line and column.
+    pub airtable: Option<AirtableConfig>,
+    pub emarsys: Option<EmarsysConfig>,
+    pub impact: Option<ImpactConfig>,
+    /// Source instances by name, several of them may share a type.
+    #[serde(default)]
+    pub sources: BTreeMap<String, SourceConfig>,
+    pub paths: PathsConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub keys: KeysConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+}
+
+/// One source instance, its settings are those of the section of its type.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SourceConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(flatten)]
+    pub settings: serde_yaml::Mapping,
+    /// Where the settings sit in the pipeline yaml, e.g. `sources.impact_eu`.
+    #[serde(skip)]
+    pub path: String,
+}
+
+impl SourceConfig {
+    /// Settings of the instance `name`, as the type they belong to. The settings are parsed
+    /// apart from the file, so errors name the yaml path of the offending key instead of
+    /// its line.
+    pub fn settings<T: DeserializeOwned>(&self, name: &str) -> Result<T, JobError> {
+        serde_path_to_error::deserialize(serde_yaml::Value::Mapping(self.settings.clone()))
+            .map_err(|err| {
+                let path = match err.path().to_string().as_str() {
+                    "." => self.path.clone(),
+                    key if key.starts_with('[') => format!("{}{}", self.path, key),
+                    key => format!("{}.{}", self.path, key),
+                };
+                JobError::Config(format!(
+                    "invalid settings of source {} at {}: {}",
+                    name,
+                    path,
+                    err.into_inner()
+                ))
+            })
+    }
+}
+
+/// Selects every source instance on the command line, so no instance may be named after it.
+pub const ALL_SOURCES: &str = "all";
+
+impl PipelineConfig {
+    /// Settings of the bucket an s3 destination writes to, none for the other backends.
+    pub fn s3_settings(&self, kind: &StorageKind) -> Option<&AwsConfig> {
+        match kind {
+            StorageKind::S3 => self.aws.as_ref(),
+            StorageKind::S3Named(name) => self.storage.s3.get(name),
+            StorageKind::Local | StorageKind::Gcs => None,
+        }
+    }
+
+    /// Every source instance by name, the `airtable`, `emarsys` and `impact` sections
+    /// included.
+    pub fn source_instances(&self) -> Result<BTreeMap<String, SourceConfig>, JobError> {
+        if self.sources.contains_key(ALL_SOURCES) {
+            return Err(JobError::Config(format!(
+                "source name {} is reserved for every source",
+                ALL_SOURCES
+            )));
+        }
+        let mut instances = self.sources.clone();
+        for (name, instance) in instances.iter_mut() {
+            instance.path = format!("sources.{}", name);
+        }
+        let sections = [
+            ("airtable", self.airtable.as_ref().map(serde_yaml::to_value)),
+            ("emarsys", self.emarsys.as_ref().map(serde_yaml::to_value)),
+            ("impact", self.impact.as_ref().map(serde_yaml::to_value)),
+        ];
+        for (kind, section) in sections {
+            // the typed sections go back to yaml so every instance is built the same way
+            let settings = match section {
+                Some(Ok(serde_yaml::Value::Mapping(settings))) => settings,
+                Some(Ok(_)) => unreachable!("a source section serializes to a mapping"),
+                Some(Err(err)) => {
+                    return Err(JobError::Config(format!("invalid {} section: {}", kind, err)))
+                }
+                None => continue,
+            };
+            if instances.contains_key(kind) {
+                return Err(JobError::Config(format!(
+                    "source {} is declared both in sources and as a section",
+                    kind
+                )));
+            }
+            instances.insert(
+                kind.to_string(),
+                SourceConfig {
+                    kind: kind.to_string(),
+                    settings,
+                    path: kind.to_string(),
+                },
+            );
+        }
+        Ok(instances)
+    }
+}
+
+/// S3 bucket and the credentials to write to it. Without static keys or a profile the
+/// standard credential chain applies: env, shared profile, web identity, instance role.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AwsConfig {
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub session_token: Option<String>,
+    /// Named profile of the shared aws config and credentials files.
+    pub profile: Option<String>,
+    /// Region of the bucket, from the credential chain when unset.
+    pub region: Option<String>,
+    pub bucket_name: String,
+    /// Role assumed with the credentials above.
+    pub role_arn: Option<String>,
+    pub role_session_name: Option<String>,
+    pub external_id: Option<String>,
+    /// S3 compatible endpoint such as minio or localstack, addressed path style.
+    pub endpoint_url: Option<String>,
+    #[serde(default)]
+    pub multipart: MultipartConfig,
+}
+
+/// Multipart uploads of the large result files, sizes in MiB.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MultipartConfig {
+    /// Files up to this size are sent with a single request.
+    pub threshold_mb: u64,
+    /// Size of every part but the last one, at least 5 MiB.
+    pub part_size_mb: u64,
+    /// Parts of one file uploaded at the same time.
+    pub parallelism: usize,
+    /// Attempts per part, the first one included.
+    pub max_attempts: u32,
+    /// Uploads the csv exports of emarsys while they are read from bigquery, without
+    /// writing them to a local file first.
+    pub stream: bool,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        MultipartConfig {
+            threshold_mb: 64,
+            part_size_mb: 16,
+            parallelism: 4,
+            max_attempts: 3,
+            stream: false,
+        }
+    }
+}
+
+impl MultipartConfig {
+    pub fn threshold_bytes(&self) -> u64 {
+        self.threshold_mb * MIB
+    }
+
+    pub fn part_size_bytes(&self) -> u64 {
+        self.part_size_mb * MIB
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AirtableConfig {
+    pub auth_token: String,
+    /// Table ids by table type, then by endpoint name.
+    #[serde(default)]
+    pub api_endpoints: HashMap<String, HashMap<String, String>>,
+    pub bases: Vec<AirtableBase>,
+    /// Limit per base url, airtable allows 5 requests per second per base.
+    #[serde(default = "airtable_rate_limit")]
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// One airtable base (a year) and the tables extracted from it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AirtableBase {
+    #[serde(deserialize_with = "string_or_number")]
+    pub year: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    pub url: String,
+    /// Overrides `airtable.auth_token`.
+    pub auth_token: Option<String>,
+    pub tables: Vec<AirtableTable>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AirtableTable {
+    #[serde(rename = "type")]
+    pub airtables_type: String,
+    pub endpoint: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Overrides `airtable.api_endpoints.{type}.{endpoint}`.
+    pub api_endpoint: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmarsysConfig {
+    pub google_project: String,
+    pub src_dataset: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImpactConfig {
+    /// Impact account credentials by campaign key.
+    pub accounts: HashMap<String, ImpactAccount>,
+    /// Campaigns file of this instance, overrides `paths.impact_campaigns`.
+    pub campaigns: Option<String>,
+    /// Limit per account sid.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImpactAccount {
+    pub sid: String,
+    pub auth_token: String,
+}
+
+/// Settings of a `rest` source: any json api described by its urls, no code needed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestConfig {
+    pub auth: Option<RestAuth>,
+    /// Headers sent with every request of the instance.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Limit shared by every table of the instance.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    pub tables: Vec<RestTable>,
+}
+
+/// How a `rest` source authenticates its requests.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RestAuth {
+    Bearer { token: String },
+    Basic { username: String, password: String },
+    /// Api key sent as the `name` header.
+    Header { name: String, value: String },
+    /// Api key sent as the `name` query parameter.
+    Query { name: String, value: String },
+}
+
+/// One endpoint of a `rest` source, a unit of work of its own.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestTable {
+    pub name: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Url of the first page, the url and the query values may use `{execution_date}`,
+    /// `{yyyy}`, `{mm}` and `{dd}`.
+    pub url: String,
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+    /// Headers of this table, on top of those of the instance.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Dot separated path to the records array of a page, e.g. `data.items`, the page
+    /// itself when unset.
+    pub records: Option<String>,
+    #[serde(default)]
+    pub pagination: Pagination,
+    /// Object fields of the records whose keys become columns of their own, nested
+    /// records can only be written as ndjson or parquet otherwise.
+    #[serde(default)]
+    pub unnest: Vec<String>,
+    /// Sql file run against the records as the table `records`, they are written as
+    /// they come when unset.
+    pub transform: Option<String>,
+}
+
+/// How the pages of an api follow each other. Paths are dot separated paths into the json
+/// body of a page.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Pagination {
+    /// A single page.
+    #[default]
+    None,
+    /// The token at `cursor` is sent back as the `param` query parameter until a page
+    /// has none.
+    Cursor { cursor: String, param: String },
+    /// The url at `next` is requested until a page has none, relative urls included.
+    NextUrl { next: String },
+    /// The `rel="next"` url of the `Link` header (RFC 5988) is requested until a page
+    /// has none.
+    Link,
+    /// Pages numbered from `start` in the `param` query parameter, until a page has no
+    /// records or the page count at `total_pages` is reached.
+    Page {
+        param: String,
+        #[serde(default = "first_page")]
+        start: u64,
+        total_pages: Option<String>,
+    },
+    /// `limit` records at a time from the `offset_param` offset, until a page comes
+    /// back short.
+    Offset {
+        offset_param: String,
+        limit_param: String,
+        limit: u64,
+    },
+}
+
+fn first_page() -> u64 {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathsConfig {
+    /// Directory holding the `airtable/{year}/{endpoint}.json` column mappings.
+    pub airtable_columns: String,
+    pub emarsys_sources: String,
+    pub emarsys_columns: String,
+    pub impact_campaigns: String,
+    /// Directory holding one `{report}.sql` per impact report.
+    pub impact_queries: String,
+    /// Directory holding one `{run_id}.json` checkpoint per run.
+    #[serde(default = "default_checkpoints")]
+    pub checkpoints: String,
+    /// Directory holding one `{run_id}/{execution_date}/` staging directory per run.
+    #[serde(default = "default_staging")]
+    pub staging: String,
+}
+
+fn default_checkpoints() -> String {
+    ".checkpoints".to_string()
+}
+
+fn default_staging() -> String {
+    "staging".to_string()
+}
+
+/// How many units of work may run at the same time, unset means unbounded.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    /// Cap shared by every source and execution date of a run.
+    pub max_units: Option<usize>,
+    /// Cap per source name, e.g. `emarsys: 2`.
+    #[serde(default)]
+    pub sources: HashMap<String, usize>,
+}
+
+/// Time limit of a unit of work in seconds, the most specific setting wins.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    #[serde(default = "default_timeout_secs")]
+    pub default_secs: u64,
+    /// Limit per source name, e.g. `emarsys: 1800`.
+    #[serde(default)]
+    pub sources: HashMap<String, u64>,
+    /// Limit per unit of work as `{source}/{key}`, e.g. `airtable/order_sheet/2023: 7200`.
+    #[serde(default)]
+    pub units: HashMap<String, u64>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            default_secs: default_timeout_secs(),
+            sources: HashMap::new(),
+            units: HashMap::new(),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn for_unit(&self, source: &str, key: &str) -> Duration {
+        let secs = self
+            .units
+            .get(&format!("{}/{}", source, key))
+            .or_else(|| self.sources.get(source))
+            .unwrap_or(&self.default_secs);
+        Duration::from_secs(*secs)
+    }
+}
+
+fn default_timeout_secs() -> u64 {
+    3600
+}
+
+/// Retry policy of the api requests, shared by every rest source.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct HttpConfig {
+    /// Attempts per request, the first one included.
+    pub max_attempts: u32,
+    /// Backoff of the retries, of the s3 parts and gcs chunks too.
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub request_timeout_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+            request_timeout_secs: 300,
+        }
+    }
+}
+
+/// Format of the result files, the most specific setting wins.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Compression of every result file, parquet compresses its pages instead.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Format per source name, e.g. `airtable: parquet`.
+    #[serde(default)]
+    pub sources: HashMap<String, OutputFormat>,
+    /// Format per table as `{source}/{table}`, where the table is an airtable endpoint,
+    /// an emarsys table, an impact report or a rest table, e.g. `emarsys/contacts: ndjson`.
+    #[serde(default)]
+    pub tables: HashMap<String, OutputFormat>,
+}
+
+impl OutputConfig {
+    pub fn for_table(&self, source: &str, table: &str) -> OutputFormat {
+        *self
+            .tables
+            .get(&format!("{}/{}", source, table))
+            .or_else(|| self.sources.get(source))
+            .unwrap_or(&self.format)
+    }
+}
+
+/// Object key layout of the result files, e.g. hive style partitions with
+/// `{source}/{table}/dt={execution_date}/{file}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeysConfig {
+    #[serde(default = "default_key_template")]
+    pub template: String,
+    /// Template per source name, overrides `template`.
+    #[serde(default)]
+    pub sources: HashMap<String, String>,
+    /// Where the `manifest.json` and `_SUCCESS` marker of a source are written, it may
+    /// not use `{table}`.
+    #[serde(default = "default_manifest_template")]
+    pub manifest: String,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            template: default_key_template(),
+            sources: HashMap::new(),
+            manifest: default_manifest_template(),
+        }
+    }
+}
+
+impl KeysConfig {
+    /// Placeholders a key template may use, `{file}` is the only required one.
+    pub const PLACEHOLDERS: [&'static str; 8] = [
+        "source",
+        "table",
+        "execution_date",
+        "yyyy",
+        "mm",
+        "dd",
+        "run_id",
+        "file",
+    ];
+
+    pub fn for_source(&self, source: &str) -> &str {
+        self.sources.get(source).unwrap_or(&self.template)
+    }
+}
+
+fn default_key_template() -> String {
+    "{source}/{execution_date}/{file}".to_string()
+}
+
+fn default_manifest_template() -> String {
+    "{execution_date}/{source}/{file}".to_string()
+}
+
+/// Problems of the s3 bucket settings at `section`.
+fn check_aws(section: &str, aws: &AwsConfig, problems: &mut Vec<String>) {
+    if aws.access_key.is_some() != aws.secret_key.is_some() {
+        problems.push(format!("{0}.access_key and {0}.secret_key go together", section));
+    }
+    if aws.access_key.is_some() && aws.profile.is_some() {
+        problems.push(format!("{0}.profile excludes {0}.access_key", section));
+    }
+    if aws.session_token.is_some() && aws.access_key.is_none() {
+        problems.push(format!("{0}.session_token needs {0}.access_key", section));
+    }
+    if aws.role_arn.is_none() && (aws.role_session_name.is_some() || aws.external_id.is_some()) {
+        problems.push(format!(
+            "{0}.role_session_name and {0}.external_id need {0}.role_arn",
+            section
+        ));
+    }
+
+    let multipart = &aws.multipart;
+    if !(5..=5120).contains(&multipart.part_size_mb) {
+        problems.push(format!("{}.multipart.part_size_mb must be between 5 and 5120", section));
+    }
+    if multipart.parallelism == 0 {
+        problems.push(format!("{}.multipart.parallelism must be at least 1", section));
+    }
+    if multipart.max_attempts == 0 {
+        problems.push(format!("{}.multipart.max_attempts must be at least 1", section));
+    }
+}
+
+/// Problems of one key template, `name` is where it is set in the config.
+fn check_key_template(name: &str, template: &str, problems: &mut Vec<String>) {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            problems.push(format!("{} has an unclosed placeholder", name));
+            return;
+        };
+        let placeholder = &rest[start + 1..start + end];
+        if !KeysConfig::PLACEHOLDERS.contains(&placeholder) {
+            problems.push(format!("{} has an unknown placeholder {{{}}}", name, placeholder));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    if !template.contains("{file}") {
+        problems.push(format!("{} must contain {{file}}", name));
+    }
+    if template.starts_with('/') {
+        problems.push(format!("{} must not start with /", name));
+    }
+}
+
+/// Where the result files are stored, the `aws` bucket unless a source or table says
+/// otherwise. Every setting takes one backend or a list of them, the files are uploaded
+/// to each of them at the same time.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: Destinations,
+    /// Required by the `local` backend.
+    pub local: Option<LocalStorageConfig>,
+    /// Required by the `gcs` backend.
+    pub gcs: Option<GcsConfig>,
+    /// S3 buckets besides the `aws` one by name, each with its own credentials, endpoint
+    /// and role. Destinations list them by name like the other backends.
+    #[serde(default)]
+    pub s3: HashMap<String, AwsConfig>,
+    /// Backends per source name, e.g. `emarsys: [s3, gcs]`.
+    #[serde(default)]
+    pub sources: HashMap<String, Destinations>,
+    /// Backends per table as `{source}/{table}`, e.g. `emarsys/contacts: local`.
+    #[serde(default)]
+    pub tables: HashMap<String, Destinations>,
+    #[serde(default)]
+    pub policy: UploadPolicy,
+}
+
+impl StorageConfig {
+    pub fn for_table(&self, source: &str, table: &str) -> &[StorageKind] {
+        &self
+            .tables
+            .get(&format!("{}/{}", source, table))
+            .or_else(|| self.sources.get(source))
+            .unwrap_or(&self.backend)
+            .0
+    }
+
+    /// Every backend the tables of `source` are stored in.
+    pub fn for_source(&self, source: &str) -> Vec<StorageKind> {
+        let prefix = format!("{}/", source);
+        let mut kinds: Vec<StorageKind> = Vec::new();
+        let tables = self
+            .tables
+            .iter()
+            .filter(|(table, _)| table.starts_with(&prefix))
+            .map(|(_, destinations)| destinations);
+        for destinations in std::iter::once(self.sources.get(source).unwrap_or(&self.backend))
+            .chain(tables)
+        {
+            for kind in &destinations.0 {
+                if !kinds.contains(kind) {
+                    kinds.push(kind.clone());
+                }
+            }
+        }
+        kinds
+    }
+
+    /// Every backend some setting uses.
+    pub fn kinds(&self) -> impl Iterator<Item = &StorageKind> {
+        std::iter::once(&self.backend)
+            .chain(self.sources.values())
+            .chain(self.tables.values())
+            .flat_map(|destinations| destinations.0.iter())
+    }
+}
+
+/// One backend or a list of them.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(from = "OneOrMany")]
+pub struct Destinations(pub Vec<StorageKind>);
+
+impl Default for Destinations {
+    fn default() -> Self {
+        Destinations(vec![StorageKind::S3])
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(StorageKind),
+    Many(Vec<StorageKind>),
+}
+
+impl From<OneOrMany> for Destinations {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(kind) => Destinations(vec![kind]),
+            OneOrMany::Many(kinds) => Destinations(kinds),
+        }
+    }
+}
+
+/// Which uploads of a result file must succeed for its unit of work to succeed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadPolicy {
+    /// Every destination.
+    #[default]
+    All,
+    /// The first destination listed, the others are best effort.
+    First,
+    /// At least one destination.
+    Any,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// The `aws` bucket.
+    #[default]
+    S3,
+    /// A local directory, for development and tests.
+    Local,
+    Gcs,
+    /// One of the named buckets of `storage.s3`.
+    #[serde(untagged)]
+    S3Named(String),
+}
+
+impl StorageKind {
+    pub fn name(&self) -> &str {
+        match self {
+            StorageKind::S3 => "s3",
+            StorageKind::Local => "local",
+            StorageKind::Gcs => "gcs",
+            StorageKind::S3Named(name) => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalStorageConfig {
+    /// Directory the objects are copied to, under the same keys as in the bucket.
+    pub dir: String,
+}
+
+/// Google cloud storage bucket, authenticated with `credentials_file` when set and with
+/// the application default credentials otherwise.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GcsConfig {
+    pub bucket_name: String,
+    /// Service account key file.
+    pub credentials_file: Option<String>,
+    /// Overrides the storage api url, e.g. `http://localhost:4443` for fake-gcs-server.
+    pub endpoint: Option<String>,
+    /// Sends no credentials at all, for emulators.
+    #[serde(default)]
+    pub anonymous: bool,
+    /// Size of the chunks of the resumable uploads in MiB.
+    #[serde(default = "default_gcs_chunk_size_mb")]
+    pub chunk_size_mb: u64,
+    /// Attempts per chunk, the first one included.
+    #[serde(default = "default_gcs_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl GcsConfig {
+    pub fn chunk_size_bytes(&self) -> u64 {
+        self.chunk_size_mb * MIB
+    }
+}
+
+fn default_gcs_chunk_size_mb() -> u64 {
+    16
+}
+
+fn default_gcs_max_attempts() -> u32 {
+    3
+}
+
+/// Token bucket refilled at `requests_per_second`, holding up to `burst` requests. Checked
+/// as it is read, a rate that is not positive would stall or panic the limiter.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "RawRateLimit")]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    /// Defaults to one second worth of requests.
+    pub burst: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimit {
+    requests_per_second: f64,
+    burst: Option<u32>,
+}
+
+impl TryFrom<RawRateLimit> for RateLimit {
+    type Error = String;
+
+    fn try_from(raw: RawRateLimit) -> Result<Self, Self::Error> {
+        let rate = raw.requests_per_second;
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(format!(
+                "requests_per_second must be positive, got {}",
+                rate
+            ));
+        }
+        if raw.burst == Some(0) {
+            return Err("burst must be at least 1".to_string());
+        }
+        Ok(RateLimit {
+            requests_per_second: rate,
+            burst: raw.burst,
+        })
+    }
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> u32 {
+        self.burst
+            .unwrap_or(self.requests_per_second.ceil() as u32)
+            .max(1)
+    }
+}
+
+fn airtable_rate_limit() -> Option<RateLimit> {
+    Some(RateLimit {
+        requests_per_second: 5.0,
+        burst: None,
+    })
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_yaml::Value::deserialize(deserializer)? {
+        serde_yaml::Value::String(value) => Ok(value),
+        serde_yaml::Value::Number(value) => Ok(value.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or a number, got {:?}",
+            other
+        ))),
+    }
+}
+
 #[tracing::instrument(err)]
-pub async fn get_config() -> Result<Value, serde_yaml::Error> {
-    let file_path = env::var("PIPELINE_CONFIG").expect("$PIPELINE_CONFIG is not set");
-    let mut file = fs::File::open(file_path).expect("file should open read only");
+pub fn load_config(file_path: &str) -> Result<PipelineConfig, Box<dyn std::error::Error>> {
+    let contents = read_file(file_path)?;
+
+    // serde_yaml reports the line and column of the offending key
+    serde_yaml::from_str(&contents)
+        .map_err(|err| format!("invalid pipeline config {}: {}", file_path, err).into())
+}
+
+/// Problem of a file referenced by the config that does not exist.
+pub fn check_file(path: &str, problems: &mut Vec<String>) {
+    if !Path::new(path).is_file() {
+        problems.push(format!("file {} does not exist", path));
+    }
+}
+
+/// Checks the settings shared by every source, returning one message per problem found.
+/// The settings of each source instance are checked by the source itself.
+pub async fn validate_config(config: &PipelineConfig) -> Vec<String> {
+    let mut problems = check_config(config);
+    if let Some(file) = config.storage.gcs.as_ref().and_then(|gcs| gcs.credentials_file.clone()) {
+        check_file(&file, &mut problems);
+    }
+    problems
+}
+
+/// The checks of `validate_config` that read no file, cheap enough to run before every run.
+pub fn check_config(config: &PipelineConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let instances = config.source_instances().unwrap_or_else(|err| {
+        problems.push(err.to_string());
+        BTreeMap::new()
+    });
+    let known = |source: &str| instances.contains_key(source);
+
+    if config.concurrency.max_units == Some(0) {
+        problems.push("concurrency.max_units must be at least 1".to_string());
+    }
+    for (source, limit) in &config.concurrency.sources {
+        if !known(source) {
+            problems.push(format!("concurrency.sources.{} is not a known source", source));
+        } else if *limit == 0 {
+            problems.push(format!("concurrency.sources.{} must be at least 1", source));
+        }
+    }
 
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Unable to read file");
+    let timeouts = &config.timeouts;
+    if timeouts.default_secs == 0 {
+        problems.push("timeouts.default_secs must be at least 1".to_string());
+    }
+    for (source, secs) in &timeouts.sources {
+        if !known(source) {
+            problems.push(format!("timeouts.sources.{} is not a known source", source));
+        } else if *secs == 0 {
+            problems.push(format!("timeouts.sources.{} must be at least 1", source));
+        }
+    }
+    for (unit, secs) in &timeouts.units {
+        let source = unit.split('/').next().unwrap_or_default();
+        if !known(source) {
+            problems.push(format!("timeouts.units.{} does not start with a known source", unit));
+        } else if *secs == 0 {
+            problems.push(format!("timeouts.units.{} must be at least 1", unit));
+        }
+    }
 
-    let config: Result<Value, serde_yaml::Error> = serde_yaml::from_str(&contents);
+    for source in config.output.sources.keys() {
+        if !known(source) {
+            problems.push(format!("output.sources.{} is not a known source", source));
+        }
+    }
+    for table in config.output.tables.keys() {
+        let source = table.split('/').next().unwrap_or_default();
+        if !known(source) {
+            problems.push(format!("output.tables.{} does not start with a known source", table));
+        }
+    }
 
-    config
+    check_key_template("keys.template", &config.keys.template, &mut problems);
+    for (source, template) in &config.keys.sources {
+        if !known(source) {
+            problems.push(format!("keys.sources.{} is not a known source", source));
+        } else {
+            check_key_template(&format!("keys.sources.{}", source), template, &mut problems);
+        }
+    }
+    // instances of one type name their files alike, only {source} keeps their keys apart
+    for (name, instance) in &instances {
+        let template = config.keys.for_source(name);
+        let collides = instances
+            .iter()
+            .take_while(|(other, _)| *other != name)
+            .find(|(other, other_instance)| {
+                other_instance.kind == instance.kind && config.keys.for_source(other) == template
+            });
+        if let (false, Some((other, _))) = (template.contains("{source}"), collides) {
+            problems.push(format!(
+                "sources {} and {} of type {} share a key template without {{source}}, their objects collide",
+                other, name, instance.kind
+            ));
+        }
+    }
+    check_key_template("keys.manifest", &config.keys.manifest, &mut problems);
+    if config.keys.manifest.contains("{table}") {
+        problems.push("keys.manifest must not contain {table}".to_string());
+    }
+
+    for source in config.storage.sources.keys() {
+        if !known(source) {
+            problems.push(format!("storage.sources.{} is not a known source", source));
+        }
+    }
+    for table in config.storage.tables.keys() {
+        let source = table.split('/').next().unwrap_or_default();
+        if !known(source) {
+            problems.push(format!("storage.tables.{} does not start with a known source", table));
+        }
+    }
+    let settings = std::iter::once(("storage.backend".to_string(), &config.storage.backend))
+        .chain(config.storage.sources.iter().map(|(source, destinations)| {
+            (format!("storage.sources.{}", source), destinations)
+        }))
+        .chain(config.storage.tables.iter().map(|(table, destinations)| {
+            (format!("storage.tables.{}", table), destinations)
+        }));
+    for (name, destinations) in settings {
+        let kinds = &destinations.0;
+        if kinds.is_empty() {
+            problems.push(format!("{} lists no backend", name));
+        } else if kinds.iter().enumerate().any(|(i, kind)| kinds[..i].contains(kind)) {
+            problems.push(format!("{} lists a backend twice", name));
+        }
+    }
+    let uses = |backend: StorageKind| config.storage.kinds().any(|kind| *kind == backend);
+    if uses(StorageKind::Local) && config.storage.local.is_none() {
+        problems.push("the local storage backend needs storage.local.dir".to_string());
+    }
+    if uses(StorageKind::Gcs) && config.storage.gcs.is_none() {
+        problems.push("the gcs storage backend needs storage.gcs".to_string());
+    }
+    let mut unknown: Vec<&str> = config
+        .storage
+        .kinds()
+        .filter_map(|kind| match kind {
+            StorageKind::S3Named(name) if !config.storage.s3.contains_key(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    unknown.sort();
+    unknown.dedup();
+    for name in unknown {
+        problems.push(format!(
+            "storage backend {} is neither s3, local, gcs nor a bucket of storage.s3",
+            name
+        ));
+    }
+    if let Some(gcs) = &config.storage.gcs {
+        if gcs.chunk_size_mb == 0 {
+            problems.push("storage.gcs.chunk_size_mb must be at least 1".to_string());
+        }
+        if gcs.max_attempts == 0 {
+            problems.push("storage.gcs.max_attempts must be at least 1".to_string());
+        }
+        if gcs.anonymous && gcs.credentials_file.is_some() {
+            problems.push("storage.gcs.anonymous excludes storage.gcs.credentials_file".to_string());
+        }
+    }
+
+    if uses(StorageKind::S3) {
+        match &config.aws {
+            Some(aws) => check_aws("aws", aws, &mut problems),
+            None => problems.push("the s3 storage backend needs the aws section".to_string()),
+        }
+    }
+    let mut buckets: Vec<&String> = config.storage.s3.keys().collect();
+    buckets.sort();
+    for name in buckets {
+        let section = format!("storage.s3.{}", name);
+        if ["s3", "local", "gcs"].contains(&name.as_str()) {
+            problems.push(format!("{} is named after a backend, destinations cannot list it", section));
+        }
+        check_aws(&section, &config.storage.s3[name], &mut problems);
+    }
+
+    if config.http.max_attempts == 0 {
+        problems.push("http.max_attempts must be at least 1".to_string());
+    }
+    if config.http.base_delay_ms > config.http.max_delay_ms {
+        problems.push("http.base_delay_ms is larger than http.max_delay_ms".to_string());
+    }
+
+    problems
 }
 
 pub async fn setup_emarsys_sources_tables(
-) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let config = get_config().await?;
-    let emarsys_google_project = config["emarsys_google_project"].as_str().unwrap();
-    let emarsys_src_dataset = &config["emarsys_src_dataset"].as_str().unwrap();
-    let execution_date = env::var("CURRENT_DATE").expect("$PIPELINE_CONFIG is not set");
+    config: &PipelineConfig,
+    settings: &EmarsysConfig,
+    execution_date: &str,
+) -> Result<HashMap<String, String>, JobError> {
+    let emarsys_google_project = settings.google_project.as_str();
+    let emarsys_src_dataset = settings.src_dataset.as_str();
 
     let sql_filter = format!(
         "where date(loaded_at, \"Asia/Singapore\") = \"{}\"",
         execution_date
     );
-    let contents = fs::read_to_string(&config["emarsys_bq_sources"].as_str().unwrap())
-        .expect("Could not read the file");
+    let path = &config.paths.emarsys_sources;
+    let contents = read_file(path)?;
 
     let emarsys_data_sources: HashMap<String, String> =
-        serde_json::from_str::<HashMap<String, Value>>(&contents)
-            .expect("Failed to parse JSON")
+        parse_json_file(&contents, path)?
             .iter()
             .map(|(k, v)| {
-                (
+                let query = json_str(v, path, k)?;
+                Ok((
                     k.clone(),
-                    v.as_str()
-                        .expect("Failed to convert value to str")
-                        .to_string()
+                    query
                         .replace("{EMARSYS_GOOGLE_PROJECT}", emarsys_google_project)
                         .replace("{SRC_DATASET}", emarsys_src_dataset)
                         .replace("{SQL_FILTER}", sql_filter.as_str()),
-                )
+                ))
             })
-            .collect();
+            .collect::<Result<_, JobError>>()?;
 
     Ok(emarsys_data_sources)
 }
 
-pub async fn setup_emarsys_columns() -> Result<HashMap<String, String>, Box<dyn std::error::Error>>
-{
-    let config = get_config().await?;
-
-    let contents = fs::read_to_string(&config["emarsys_bq_columns"].as_str().unwrap())
-        .expect("Could not read the file");
+pub async fn setup_emarsys_columns(
+    config: &PipelineConfig,
+) -> Result<HashMap<String, String>, JobError> {
+    let path = &config.paths.emarsys_columns;
+    let contents = read_file(path)?;
 
     let datalake_emarsys: HashMap<String, String> =
-        serde_json::from_str::<HashMap<String, Value>>(&contents)
-            .expect("Failed to parse JSON")
+        parse_json_file(&contents, path)?
             .iter()
-            .map(|(k, v)| {
-                (
-                    k.clone(),
-                    v.as_str()
-                        .expect("Failed to convert value to str")
-                        .to_string(),
-                )
-            })
-            .collect();
+            .map(|(k, v)| Ok((k.clone(), json_str(v, path, k)?.to_string())))
+            .collect::<Result<_, JobError>>()?;
 
     Ok(datalake_emarsys)
 }
 
-pub async fn setup_campaigns() -> Result<HashMap<String, Vec<String>> ,Box<dyn std::error::Error>>{
-    let config = get_config().await?;
-
-    let contents = fs::read_to_string(&config["impact_campaigns_path"].as_str().unwrap())
-        .expect("Could not read the file");
+pub async fn setup_campaigns(
+    config: &PipelineConfig,
+    settings: &ImpactConfig,
+) -> Result<HashMap<String, Vec<String>>, JobError> {
+    let path = settings
+        .campaigns
+        .as_ref()
+        .unwrap_or(&config.paths.impact_campaigns);
+    let contents = read_file(path)?;
 
     let campaigns: HashMap<String,  Vec<String>> =
-        serde_json::from_str::<HashMap<String, Value>>(&contents)
-            .expect("Failed to parse JSON")
+        parse_json_file(&contents, path)?
             .iter()
-            .map(|(k, v)| {
-                (
-                    k.clone(),
-                    v.as_array().unwrap().clone().into_iter().map(
-                        |x| x.to_string()
-                    ).collect()
-                )
+            .map(|(k, v)| match v.as_array() {
+                // the currency and the sub account name of the campaign
+                Some(detail) if detail.len() >= 2 => {
+                    Ok((k.clone(), detail.iter().map(|x| x.to_string()).collect()))
+                }
+                _ => Err(JobError::Config(format!(
+                    "{} in {} must be a list of a currency and a sub account name, got {}",
+                    k, path, v
+                ))),
             })
-            .collect();
+            .collect::<Result<_, JobError>>()?;
 
     Ok(campaigns)
-}
\ No newline at end of file
+}
+
+fn read_file(path: &str) -> Result<String, JobError> {
+    fs::read_to_string(path)
+        .map_err(|err| JobError::Config(format!("could not read {}: {}", path, err)))
+}
+
+/// String value of `key` in the json file `path`.
+fn json_str<'a>(value: &'a Value, path: &str, key: &str) -> Result<&'a str, JobError> {
+    value.as_str().ok_or_else(|| {
+        JobError::Config(format!("{} in {} must be a string, got {}", key, path, value))
+    })
+}
+
+fn parse_json_file(contents: &str, path: &str) -> Result<HashMap<String, Value>, JobError> {
+    serde_json::from_str(contents)
+        .map_err(|err| JobError::Config(format!("invalid json in {}: {}", path, err)))
+}
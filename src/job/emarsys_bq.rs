@@ -1,124 +1,485 @@
-use super::job::EmarsysBq;
-use crate::job::config::{setup_emarsys_columns, setup_emarsys_sources_tables};
-use crate::job::job::{AwsS3, StoragePlatform, Tasks};
+use super::error::JobError;
+use super::job::{join_units, EmarsysBq, RunContext, Source};
+use super::registry::UnitFilters;
+use crate::job::config::{
+    check_file, setup_emarsys_columns, setup_emarsys_sources_tables, SourceConfig, StorageKind,
+};
+use crate::job::job::Tasks;
+use futures::future::BoxFuture;
 use csv;
 use google_cloud_bigquery::{
     client::{Client, ClientConfig},
     http::job::query::QueryRequest,
     query::row::Row,
 };
-use polars::prelude::IntoVec;
-use std::time::Duration as DurationStd;
-use tokio::time::timeout;
-use tracing::{error, info};
+use super::manifest::ColumnSchema;
+use super::plan::PlanEntry;
+use super::summary::{DestinationReport, UnitReport, UnitStats};
+use super::output::{Compression, OutputFormat, SharedBuffer};
+use super::s3_storage::MultipartUpload;
+use polars::prelude::{DataFrame, DataType, Field, IntoVec, NamedFrom, Schema, Series};
+use std::collections::HashMap;
+use tracing::info;
 
-impl Tasks for EmarsysBq {
-    #[tracing::instrument(err)]
-    async fn extraction(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let sources_tables = setup_emarsys_sources_tables().await?;
-        let datalake_emarsys = setup_emarsys_columns().await?;
+/// Rows of a parquet, ndjson or avro export gathered before they are written.
+const BATCH_ROWS: usize = 100_000;
 
-        let columns = datalake_emarsys.get(self.table_name.as_str()).unwrap();
-        let source_table = sources_tables.get(self.table_name.as_str()).unwrap();
+impl EmarsysBq {
+    /// Filters the units of work can be narrowed down by.
+    pub const FILTERS: &'static [&'static str] = &["table"];
 
-        let request = QueryRequest {
-            query: format!(
-                r#"
+    pub fn build(
+        name: &str,
+        config: &SourceConfig,
+        context: RunContext,
+        filters: &UnitFilters,
+    ) -> Result<Box<dyn Source>, JobError> {
+        Ok(Box::new(EmarsysBq {
+            context,
+            name: name.to_string(),
+            settings: config.settings(name)?,
+            table_name: String::new(),
+            table_filter: filters.get("table").cloned(),
+        }))
+    }
+
+    /// Table keys to extract, narrowed down by the table filter.
+    pub fn selected_tables(
+        &self,
+        sources_tables: &HashMap<String, String>,
+    ) -> Result<Vec<String>, JobError> {
+        let mut tables: Vec<String> = sources_tables.keys().into_vec();
+
+        if let Some(table) = &self.table_filter {
+            tables.retain(|name| name == table);
+            if tables.is_empty() {
+                return Err(JobError::Config(format!(
+                    "emarsys table {} is not defined in the sources",
+                    table
+                )));
+            }
+        }
+
+        Ok(tables)
+    }
+
+    /// Select query of `table_name` with its source table template already rendered.
+    pub fn query(
+        &self,
+        sources_tables: &HashMap<String, String>,
+        datalake_emarsys: &HashMap<String, String>,
+    ) -> Result<String, JobError> {
+        let columns = datalake_emarsys
+            .get(self.table_name.as_str())
+            .ok_or_else(|| {
+                JobError::Config(format!("no columns defined for emarsys table {}", self.table_name))
+            })?;
+        let source_table = sources_tables
+            .get(self.table_name.as_str())
+            .ok_or_else(|| {
+                JobError::Config(format!("no source defined for emarsys table {}", self.table_name))
+            })?;
+
+        Ok(format!(
+            r#"
                 SELECT 
                     {}
                 FROM 
                     {}
                 "#,
-                *columns, source_table
-            ),
-            ..Default::default()
-        };
+            columns, source_table
+        ))
+    }
 
-        let (config, project_id) = ClientConfig::new_with_auth().await?;
-        let client = Client::new(config).await?;
+    pub fn output_format(&self) -> OutputFormat {
+        self.context.output_format(self.source(), &self.table_name)
+    }
 
-        let mut iter: google_cloud_bigquery::query::Iterator<Row> =
-            client.query(&project_id.unwrap(), request).await?;
+    pub fn result_file(&self) -> String {
+        format!("{}.{}", self.table_name, self.output_format().extension())
+    }
 
-        let file_name = format!("{}.csv", self.table_name.as_str());
+    /// The s3 bucket the export is uploaded to while it is read, without a local file.
+    /// Only a table stored in a single s3 bucket streams.
+    pub fn stream_destination(&self) -> Option<&StorageKind> {
+        let config = &self.context.config;
+        match config.storage.for_table(self.source(), &self.table_name) {
+            [kind]
+                if self.output_format() == OutputFormat::Csv
+                    && config.s3_settings(kind).is_some_and(|aws| aws.multipart.stream) =>
+            {
+                Some(kind)
+            }
+            _ => None,
+        }
+    }
 
-        let mut writer = csv::Writer::from_path(&file_name)?;
+    /// Runs the query of the table, returns the rows and the select expressions of its
+    /// columns.
+    async fn query_rows(
+        &self,
+    ) -> Result<(google_cloud_bigquery::query::Iterator<Row>, Vec<String>), JobError> {
+        let sources_tables = setup_emarsys_sources_tables(
+            &self.context.config,
+            &self.settings,
+            &self.context.execution_date,
+        )
+        .await?;
+        let datalake_emarsys = setup_emarsys_columns(&self.context.config).await?;
 
-        let vect_col: Vec<_> = datalake_emarsys
+        let request = QueryRequest {
+            query: self.query(&sources_tables, &datalake_emarsys)?,
+            ..Default::default()
+        };
+
+        let (config, project_id) = ClientConfig::new_with_auth()
+            .await
+            .map_err(|err| JobError::Config(format!("bigquery auth failed: {}", err)))?;
+        let client = Client::new(config)
+            .await
+            .map_err(|err| JobError::Http(err.to_string()))?;
+        let project_id = project_id.ok_or_else(|| {
+            JobError::Config("no google project found in the bigquery credentials".to_string())
+        })?;
+
+        let iter = client
+            .query(&project_id, request)
+            .await
+            .map_err(|err| JobError::Http(err.to_string()))?;
+
+        let columns = datalake_emarsys
             .get(self.table_name.as_str())
-            .unwrap()
+            .ok_or_else(|| {
+                JobError::Config(format!(
+                    "emarsys table {} has no columns in {}",
+                    self.table_name, self.context.config.paths.emarsys_columns
+                ))
+            })?
             .split(",")
+            .map(String::from)
             .collect();
+
+        Ok((iter, columns))
+    }
+
+    /// Columns of the result file, every value is written as a string.
+    async fn schema(&self) -> Result<Vec<ColumnSchema>, JobError> {
+        let datalake_emarsys = setup_emarsys_columns(&self.context.config).await?;
+        let columns = datalake_emarsys.get(self.table_name.as_str()).ok_or_else(|| {
+            JobError::Config(format!("no columns defined for emarsys table {}", self.table_name))
+        })?;
+
+        Ok(columns
+            .split(",")
+            .map(|expression| ColumnSchema {
+                name: column_name(expression),
+                data_type: "str".to_string(),
+            })
+            .collect())
+    }
+
+    /// Uploads the csv export part by part while the rows are read.
+    async fn stream(&mut self, destination: &StorageKind) -> Result<UnitStats, JobError> {
+        let (mut iter, columns) = self.query_rows().await?;
+
+        let format = self.output_format();
+        let compression = self.context.config.output.compression;
+        let key = self.context.upload_key(
+            self.source(),
+            &self.table_name,
+            &self.result_file(),
+            format,
+        );
+        let s3 = self.context.storage.s3(destination)?;
+        let part_size = s3.settings.multipart.part_size_bytes() as usize;
+
+        let mut upload = s3
+            .start_multipart(&key, format.content_type(), compression.content_encoding(format))
+            .await?;
+
+        // a failed upload is aborted once it is dropped
+        let records =
+            stream_rows(&mut iter, columns.len(), &mut upload, part_size, compression).await?;
+
+        let bytes = upload.bytes();
+        let sha256 = upload.complete().await?;
+        info!("success stream table  : {}", self.table_name.as_str());
+
+        Ok(UnitStats {
+            rows: records,
+            bytes,
+            s3_key: key,
+            sha256,
+            schema: self.schema().await?,
+            destinations: vec![DestinationReport::new(destination.name(), &Ok(()))],
+            resumed: false,
+        })
+    }
+}
+
+/// Writes the rows as csv into the parts of `upload`, returns how many were written.
+async fn stream_rows(
+    iter: &mut google_cloud_bigquery::query::Iterator<Row>,
+    col_size: usize,
+    upload: &mut MultipartUpload,
+    part_size: usize,
+    compression: Compression,
+) -> Result<usize, JobError> {
+    let buffer = SharedBuffer::default();
+    let mut writer = csv::Writer::from_writer(compression.writer(buffer.clone())?);
+    let mut records = 0;
+
+    while let Some(row) = iter
+        .next()
+        .await
+        .map_err(|err| JobError::Http(err.to_string()))?
+    {
+        writer.write_record(csv_record(&row, col_size))?;
+        records += 1;
+
+        if buffer.len() >= upload.next_part_size(part_size) {
+            upload.push_part(buffer.take()).await?;
+        }
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| JobError::Storage(format!("could not write the csv export: {}", err)))?
+        .finish()?;
+    let rest = buffer.take();
+    // an upload needs at least one part, even an empty one
+    if !rest.is_empty() || upload.bytes() == 0 {
+        upload.push_part(rest).await?;
+    }
+
+    Ok(records)
+}
+
+/// Takes the rows gathered in `values`, one vector per column, as a dataframe.
+fn batch(names: &[String], values: &mut [Vec<Option<String>>]) -> Result<DataFrame, JobError> {
+    let columns: Vec<Series> = names
+        .iter()
+        .zip(values.iter_mut())
+        .map(|(name, column)| Series::new(name, std::mem::take(column)))
+        .collect();
+    Ok(DataFrame::new(columns)?)
+}
+
+/// Csv record of a row, null or unreadable values are written as `None`.
+fn csv_record(row: &Row, col_size: usize) -> Vec<String> {
+    (0..col_size)
+        .map(|x| match row.column::<Option<String>>(x) {
+            Ok(Some(data)) => data,
+            Ok(None) => "None".to_string(),
+            Err(_) => "None".to_string(),
+        })
+        .collect()
+}
+
+/// Name of the column a select expression produces: its alias, else the last part of the
+/// column path.
+fn column_name(expression: &str) -> String {
+    let expression = expression.trim();
+    let name = match expression.to_lowercase().rfind(" as ") {
+        Some(alias) => &expression[alias + 4..],
+        None => expression.rsplit('.').next().unwrap_or(expression),
+    };
+    name.trim().trim_matches('`').to_string()
+}
+
+impl Tasks for EmarsysBq {
+    fn source(&self) -> &str {
+        &self.name
+    }
+
+    #[tracing::instrument(err)]
+    async fn extraction(&mut self) -> Result<usize, JobError> {
+        let (mut iter, vect_col) = self.query_rows().await?;
+
+        let format = self.output_format();
+        let file_name = self.context.output_path(self.source(), &self.result_file())?;
+
         let col_size = vect_col.len();
+        let mut records = 0;
 
-        while let Some(row) = iter.next().await? {
-            let result: Vec<String> = (0..col_size)
-                .map(|x| match row.column::<Option<String>>(x) {
-                    Ok(Some(data)) => data,
-                    Ok(None) => "None".to_string(),
-                    Err(_) => "None".to_string(),
-                })
-                .collect();
+        // csv is streamed row by row as before, the typed formats go through a dataframe
+        // per batch of rows
+        if format == OutputFormat::Csv {
+            let mut writer = csv::Writer::from_path(&file_name)?;
 
-            match writer.write_record(&result) {
-                Ok(()) => (),
-                Err(err) => error!(
-                    "error write row into table :{}\nMessage: {:?}",
-                    self.table_name.as_str(),
-                    err
-                ),
+            while let Some(row) = iter
+                .next()
+                .await
+                .map_err(|err| JobError::Http(err.to_string()))?
+            {
+                writer.write_record(csv_record(&row, col_size))?;
+                records += 1;
             }
-        }
 
-        match writer.flush() {
-            Ok(()) => {
-                info!("success write table  : {}", self.table_name.as_str());
-                StoragePlatform::upload(
-                    AwsS3 {
-                        config: None,
-                        client: None,
-                        bucket_name: None,
-                    },
-                    file_name,
-                )
-                .await?;
+            writer.flush()?;
+        } else {
+            let names: Vec<String> =
+                vect_col.iter().map(|expression| column_name(expression)).collect();
+            let schema: Schema = names
+                .iter()
+                .map(|name| Field::new(name, DataType::String))
+                .collect();
+            let mut writer =
+                format.batched(&file_name, &schema, self.context.config.output.compression)?;
+            let mut values: Vec<Vec<Option<String>>> = vec![Vec::new(); col_size];
+
+            while let Some(row) = iter
+                .next()
+                .await
+                .map_err(|err| JobError::Http(err.to_string()))?
+            {
+                for (x, column) in values.iter_mut().enumerate() {
+                    column.push(row.column::<Option<String>>(x).ok().flatten());
+                }
+                records += 1;
+                if records % BATCH_ROWS == 0 {
+                    writer.write_batch(batch(&names, &mut values)?)?;
+                }
             }
-            Err(err) => error!("error write into {} {:?}", self.table_name.as_str(), err),
+            if records % BATCH_ROWS != 0 {
+                writer.write_batch(batch(&names, &mut values)?)?;
+            }
+            writer.finish()?;
         }
+        info!("success write table  : {}", self.table_name.as_str());
 
-        Ok(())
+        Ok(records)
     }
 
     #[tracing::instrument(err)]
-    async fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        Ok(())
+    async fn execute(&mut self) -> Result<UnitStats, JobError> {
+        if let Some(destination) = self.stream_destination().cloned() {
+            return self.stream(&destination).await;
+        }
+
+        let records = self.extraction().await?;
+
+        let object = self.context.output_object(
+            self.source(),
+            &self.table_name,
+            &self.result_file(),
+            self.output_format(),
+        )
+        .await?;
+        let mut stats = UnitStats::for_object(&object, records, self.schema().await?)?;
+        stats.destinations = self.context.upload(self.source(), &self.table_name, &object).await?;
+        Ok(stats)
     }
+}
 
-    #[tracing::instrument(err)]
-    async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let sources_tables_hashmap = setup_emarsys_sources_tables().await?;
-        let sources_tables: Vec<String> = sources_tables_hashmap.keys().into_vec();
+impl EmarsysBq {
+    #[tracing::instrument(err, skip_all, fields(source = %self.name))]
+    async fn run_units(&self) -> Result<Vec<UnitReport>, JobError> {
+        let sources_tables_hashmap = setup_emarsys_sources_tables(
+            &self.context.config,
+            &self.settings,
+            &self.context.execution_date,
+        )
+        .await?;
+        let sources_tables = self.selected_tables(&sources_tables_hashmap)?;
 
         let handles: Vec<_> = sources_tables
             .into_iter()
             .map(|table| {
                 let mut bq_job_clone = self.clone();
                 bq_job_clone.table_name = table.to_string();
-                tokio::spawn(async move {
-                    if let Err(err) =
-                        timeout(DurationStd::from_secs(3600), bq_job_clone.extraction()).await
-                    {
-                        eprintln!("Error executing task: {}", err);
-                    }
-                })
+                let handle = self.context.spawn_unit(
+                    self.source(),
+                    &table,
+                    self.context.result_paths(
+                        self.source(),
+                        &bq_job_clone.result_file(),
+                        bq_job_clone.output_format(),
+                    ),
+                    async move { bq_job_clone.execute().await },
+                );
+                (table, handle)
             })
             .collect();
 
-        // Wait for all tasks to complete
-        for handle in handles {
-            handle.await.expect("Failed to join task");
-        }
+        Ok(join_units(&self.context, self.source(), handles).await)
+    }
+
+    #[tracing::instrument(err, skip_all)]
+    async fn plan_units(&self) -> Result<Vec<PlanEntry>, JobError> {
+        let sources_tables = setup_emarsys_sources_tables(
+            &self.context.config,
+            &self.settings,
+            &self.context.execution_date,
+        )
+        .await?;
+        let datalake_emarsys = setup_emarsys_columns(&self.context.config).await?;
+
+        self.selected_tables(&sources_tables)?
+            .into_iter()
+            .map(|table| {
+                let mut bq_job = self.clone();
+                bq_job.table_name = table;
+                Ok(PlanEntry {
+                    execution_date: self.context.execution_date.clone(),
+                    source: self.source().to_string(),
+                    key: bq_job.table_name.clone(),
+                    request: bq_job.query(&sources_tables, &datalake_emarsys)?,
+                    s3_key: self.context.upload_key(
+                        self.source(),
+                        &bq_job.table_name,
+                        &bq_job.result_file(),
+                        bq_job.output_format(),
+                    ),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Source for EmarsysBq {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<Vec<UnitReport>, JobError>> {
+        Box::pin(self.run_units())
+    }
+
+    fn plan(&self) -> BoxFuture<'_, Result<Vec<PlanEntry>, JobError>> {
+        Box::pin(self.plan_units())
+    }
+
+    fn validate(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut problems = Vec::new();
+            let paths = &self.context.config.paths;
+            check_file(&paths.emarsys_sources, &mut problems);
+            check_file(&paths.emarsys_columns, &mut problems);
+
+            match (
+                setup_emarsys_sources_tables(&self.context.config, &self.settings, "").await,
+                setup_emarsys_columns(&self.context.config).await,
+            ) {
+                (Ok(sources_tables), Ok(datalake_emarsys)) => {
+                    for table in sources_tables.keys() {
+                        if !datalake_emarsys.contains_key(table) {
+                            problems.push(format!("emarsys table {} has no columns defined", table));
+                        }
+                    }
+                }
+                (sources_tables, datalake_emarsys) => {
+                    for err in [sources_tables.err(), datalake_emarsys.err()].into_iter().flatten() {
+                        problems.push(err.to_string());
+                    }
+                }
+            }
+
+            problems
+        })
+    }
 
-        Ok(())
+    fn filtered(&self) -> bool {
+        self.table_filter.is_some()
     }
 }
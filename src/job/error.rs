@@ -0,0 +1,103 @@
+use std::fmt;
+use std::time::Duration;
+
+use polars::prelude::PolarsError;
+
+use super::http::redact;
+use super::summary::DestinationReport;
+
+/// Failure of a source or of one of its units of work, classified by the stage that failed.
+#[derive(Debug)]
+pub enum JobError {
+    Config(String),
+    Http(String),
+    /// The api rejected the credentials, retrying will not help.
+    Auth { status: u16, body: String },
+    /// The api rejected the request itself, e.g. a 404 or a malformed query.
+    Client { status: u16, body: String },
+    Parse(String),
+    Transform(String),
+    Storage(String),
+    /// Uploads to the storage backends failed beyond what the upload policy allows.
+    Upload(Vec<DestinationReport>),
+    Timeout(Duration),
+    Cancelled,
+    Task(String),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Config(message) => write!(f, "config error: {}", message),
+            JobError::Http(message) => write!(f, "http error: {}", message),
+            JobError::Auth { status, body } => write!(f, "auth error: {} {}", status, body),
+            JobError::Client { status, body } => write!(f, "client error: {} {}", status, body),
+            JobError::Parse(message) => write!(f, "parse error: {}", message),
+            JobError::Transform(message) => write!(f, "transform error: {}", message),
+            JobError::Storage(message) => write!(f, "storage error: {}", message),
+            JobError::Upload(reports) => {
+                let failures: Vec<String> = reports
+                    .iter()
+                    .filter_map(|report| {
+                        let error = report.error.as_ref()?;
+                        Some(format!("{}: {}", report.backend, error))
+                    })
+                    .collect();
+                write!(f, "upload error: {}", failures.join("; "))
+            }
+            JobError::Timeout(duration) => write!(f, "timed out after {:?}", duration),
+            JobError::Cancelled => write!(f, "cancelled by a shutdown signal"),
+            JobError::Task(message) => write!(f, "task error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+impl From<reqwest::Error> for JobError {
+    fn from(err: reqwest::Error) -> Self {
+        // the url of the request may carry the api key, see http::redact
+        let url = err.url().map(redact);
+        let decode = err.is_decode();
+        let err = err.without_url();
+        let message = match url {
+            Some(url) => format!("{} ({})", err, url),
+            None => err.to_string(),
+        };
+        if decode {
+            JobError::Parse(message)
+        } else {
+            JobError::Http(message)
+        }
+    }
+}
+
+impl From<serde_json::Error> for JobError {
+    fn from(err: serde_json::Error) -> Self {
+        JobError::Parse(err.to_string())
+    }
+}
+
+impl From<PolarsError> for JobError {
+    fn from(err: PolarsError) -> Self {
+        JobError::Transform(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for JobError {
+    fn from(err: std::io::Error) -> Self {
+        JobError::Storage(err.to_string())
+    }
+}
+
+impl From<csv::Error> for JobError {
+    fn from(err: csv::Error) -> Self {
+        JobError::Storage(err.to_string())
+    }
+}
+
+impl From<chrono::ParseError> for JobError {
+    fn from(err: chrono::ParseError) -> Self {
+        JobError::Config(err.to_string())
+    }
+}
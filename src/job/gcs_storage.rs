@@ -0,0 +1,260 @@
+use google_cloud_storage::client::google_cloud_auth::credentials::CredentialsFile;
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::objects::upload::{UploadObjectRequest, UploadType};
+use google_cloud_storage::http::objects::Object;
+use google_cloud_storage::http::resumable_upload_client::{
+    ChunkSize, ResumableUploadClient, UploadStatus,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use md5::Md5;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio::io::AsyncReadExt;
+use tracing::warn;
+
+use super::error::JobError;
+use super::job::{Gcs, Storage, UploadObject};
+
+impl Storage for Gcs {
+    async fn init(&mut self) -> Result<(), JobError> {
+        let mut config = if self.settings.anonymous {
+            ClientConfig::default().anonymous()
+        } else if let Some(file) = &self.settings.credentials_file {
+            let credentials = CredentialsFile::new_from_file(file.clone())
+                .await
+                .map_err(|err| JobError::Config(format!("invalid gcs credentials {}: {}", file, err)))?;
+            ClientConfig::default()
+                .with_credentials(credentials)
+                .await
+                .map_err(|err| JobError::Config(format!("gcs auth failed: {}", err)))?
+        } else {
+            ClientConfig::default()
+                .with_auth()
+                .await
+                .map_err(|err| JobError::Config(format!("gcs auth failed: {}", err)))?
+        };
+        if let Some(endpoint) = &self.settings.endpoint {
+            config.storage_endpoint = endpoint.trim_end_matches('/').to_string();
+        }
+
+        self.client = Some(Client::new(config));
+        Ok(())
+    }
+
+    /// Sends the file in chunks of `chunk_size_mb` through one resumable upload session,
+    /// retrying failed chunks and cancelling the session when one fails for good. GCS
+    /// has no SHA-256 of its own, it is kept in the object metadata and the MD5 it
+    /// computed is checked against the bytes sent.
+    async fn upload(&self, object: &UploadObject) -> Result<(), JobError> {
+        let client = self.client.as_ref().unwrap();
+        let bucket_name = &self.settings.bucket_name;
+        let metadata = Object {
+            name: object.key.clone(),
+            content_type: Some(object.content_type.clone()),
+            content_encoding: object.content_encoding.clone(),
+            metadata: Some(HashMap::from([("sha256".to_string(), object.sha256.clone())])),
+            ..Default::default()
+        };
+        let session = client
+            .prepare_resumable_upload(
+                &UploadObjectRequest {
+                    bucket: bucket_name.clone(),
+                    ..Default::default()
+                },
+                &UploadType::Multipart(Box::new(metadata)),
+            )
+            .await
+            .map_err(|err| gcs_error("could not start the upload of", &object.key, bucket_name, err))?;
+
+        let (stored, sent) = match self.upload_chunks(&session, object).await {
+            Ok(uploaded) => uploaded,
+            Err(err) => {
+                if let Err(cancel_err) = session.cancel().await {
+                    warn!("could not cancel the upload of {}: {}", object.key, cancel_err);
+                }
+                return Err(err);
+            }
+        };
+
+        let mismatch = |what: &str, expected: &str, actual: &str| {
+            JobError::Storage(format!(
+                "checksum mismatch for {} in gs://{}: expected {} {}, got {}",
+                object.key, bucket_name, what, expected, actual
+            ))
+        };
+        if sent.sha256 != object.sha256 {
+            return Err(mismatch("sha256", &object.sha256, &sent.sha256));
+        }
+        match stored.md5_hash {
+            Some(md5) if md5 != sent.md5 => Err(mismatch("md5", &sent.md5, &md5)),
+            Some(_) => Ok(()),
+            None => {
+                warn!("gs://{}/{} has no md5 to check", bucket_name, object.key);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Digests of the bytes sent in an upload, the md5 base64 encoded as GCS reports it.
+struct SentDigests {
+    sha256: String,
+    md5: String,
+}
+
+impl Gcs {
+    /// Returns the stored object and the digests of what was sent.
+    async fn upload_chunks(
+        &self,
+        session: &ResumableUploadClient,
+        object: &UploadObject,
+    ) -> Result<(Object, SentDigests), JobError> {
+        let bucket_name = &self.settings.bucket_name;
+        let mut file = tokio::fs::File::open(&object.local_path).await?;
+        let total = file.metadata().await?.len();
+        let chunk_size = self.settings.chunk_size_bytes();
+
+        let (mut sha256, mut md5) = (Sha256::new(), Md5::new());
+        let unfinished = || {
+            JobError::Storage(format!(
+                "gcs did not finish the upload of {} to gs://{}",
+                object.key, bucket_name
+            ))
+        };
+
+        // an empty object still needs one request to finish the session, and another one
+        // to learn what was stored
+        if total == 0 {
+            session
+                .upload_single_chunk(Vec::new(), 0)
+                .await
+                .map_err(|err| gcs_error("could not upload", &object.key, bucket_name, err))?;
+            let status = session
+                .status(Some(0))
+                .await
+                .map_err(|err| gcs_error("could not check", &object.key, bucket_name, err))?;
+            let UploadStatus::Ok(stored) = status else {
+                return Err(unfinished());
+            };
+            return Ok((stored, SentDigests::of(sha256, md5)));
+        }
+
+        let mut first_byte = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size.min(total - first_byte) as usize);
+            (&mut file).take(chunk_size).read_to_end(&mut chunk).await?;
+            sha256.update(&chunk);
+            md5.update(&chunk);
+            let end = first_byte + chunk.len() as u64;
+            // first byte of the chunk gcs does not hold yet
+            let mut next_byte = first_byte;
+
+            let mut attempt = 1;
+            let status = loop {
+                let range = ChunkSize::new(next_byte, end - 1, Some(total));
+                let part = chunk[(next_byte - first_byte) as usize..].to_vec();
+                match session.upload_multiple_chunk(part, &range).await {
+                    Ok(status) => break status,
+                    Err(err) if attempt < self.settings.max_attempts => {
+                        let delay = self.backoff.delay(attempt);
+                        warn!(
+                            "chunk {} of {} failed: {}, retrying in {:?}",
+                            range, object.key, err, delay
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+
+                        // the failed request may have stored part of the chunk, or all of it
+                        match self.persisted(session, total).await {
+                            Ok(Persisted::Complete(stored)) => break UploadStatus::Ok(*stored),
+                            Ok(Persisted::Upto(byte)) if byte == end => {
+                                break UploadStatus::ResumeIncomplete
+                            }
+                            Ok(Persisted::Upto(byte)) if (first_byte..end).contains(&byte) => {
+                                next_byte = byte
+                            }
+                            Ok(Persisted::Upto(byte)) => {
+                                return Err(JobError::Storage(format!(
+                                    "gcs holds {} bytes of {} in gs://{}, expected {} to {}",
+                                    byte, object.key, bucket_name, first_byte, end
+                                )))
+                            }
+                            Err(err) => warn!(
+                                "could not check the upload of {}: {}, resending chunk {}",
+                                object.key, err, range
+                            ),
+                        }
+                    }
+                    Err(err) => {
+                        return Err(gcs_error(
+                            &format!("could not upload chunk {} of", range),
+                            &object.key,
+                            bucket_name,
+                            err,
+                        ))
+                    }
+                }
+            };
+
+            first_byte = end;
+            if first_byte >= total {
+                let UploadStatus::Ok(stored) = status else {
+                    return Err(unfinished());
+                };
+                return Ok((stored, SentDigests::of(sha256, md5)));
+            }
+        }
+    }
+
+    /// How much of the upload of `session` gcs holds, from the `Range` it answers a
+    /// `Content-Range: bytes */{total}` status query with.
+    async fn persisted(
+        &self,
+        session: &ResumableUploadClient,
+        total: u64,
+    ) -> Result<Persisted, JobError> {
+        let response = self
+            .http
+            .put(session.url())
+            .header(CONTENT_RANGE, format!("bytes */{}", total))
+            .header(CONTENT_LENGTH, 0)
+            .send()
+            .await?;
+        if response.status() == StatusCode::PERMANENT_REDIRECT {
+            // `Range: bytes=0-{last byte}`, absent while no byte is stored
+            let next_byte = response
+                .headers()
+                .get(RANGE)
+                .and_then(|range| range.to_str().ok())
+                .and_then(|range| range.rsplit_once('-'))
+                .and_then(|(_, last_byte)| last_byte.parse::<u64>().ok())
+                .map_or(0, |last_byte| last_byte + 1);
+            return Ok(Persisted::Upto(next_byte));
+        }
+        let stored = response.error_for_status()?.json::<Object>().await?;
+        Ok(Persisted::Complete(Box::new(stored)))
+    }
+}
+
+/// What gcs holds of a resumable upload.
+enum Persisted {
+    /// The bytes before this one.
+    Upto(u64),
+    Complete(Box<Object>),
+}
+
+impl SentDigests {
+    fn of(sha256: Sha256, md5: Md5) -> Self {
+        SentDigests {
+            sha256: hex::encode(sha256.finalize()),
+            md5: BASE64.encode(md5.finalize()),
+        }
+    }
+}
+
+fn gcs_error<E: std::fmt::Display>(action: &str, key: &str, bucket_name: &str, err: E) -> JobError {
+    JobError::Storage(format!("{} {} to gs://{}: {}", action, key, bucket_name, err))
+}
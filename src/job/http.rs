@@ -0,0 +1,384 @@
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::warn;
+
+use super::config::{HttpConfig, RateLimit};
+use super::error::JobError;
+use super::rate_limit::RateLimiter;
+
+/// Longest part of a response body kept in an error message.
+const MAX_BODY_CHARS: usize = 1000;
+
+/// Http client shared by every rest source of a run. Transient failures (timeouts, dropped
+/// connections, 408, 429 and 5xx) are retried with jittered exponential backoff,
+/// `Retry-After` wins over the computed delay. Every attempt also waits for the rate limit
+/// of its bucket.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    settings: HttpConfig,
+    limiter: RateLimiter,
+}
+
+/// Json body of a successful response and the headers it came with.
+#[derive(Debug)]
+pub struct JsonResponse {
+    pub body: serde_json::Value,
+    pub headers: HeaderMap,
+}
+
+enum Failure {
+    Transient(JobError, Option<Duration>),
+    Fatal(JobError),
+}
+
+impl HttpClient {
+    pub fn new(settings: &HttpConfig) -> Result<Self, JobError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.request_timeout_secs))
+            .build()?;
+
+        Ok(HttpClient {
+            client,
+            settings: settings.clone(),
+            limiter: RateLimiter::default(),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// Sends `request` until it succeeds, fails for good or runs out of attempts, and
+    /// returns the json response. Requests sharing a `bucket` share its `rate_limit`.
+    pub async fn send_json(
+        &self,
+        request: RequestBuilder,
+        bucket: &str,
+        rate_limit: Option<&RateLimit>,
+    ) -> Result<JsonResponse, JobError> {
+        let mut attempt = 1;
+
+        loop {
+            let attempt_request = request.try_clone().ok_or_else(|| {
+                JobError::Http("request with a streamed body cannot be retried".to_string())
+            })?;
+            if let Some(rate_limit) = rate_limit {
+                self.limiter.acquire(bucket, rate_limit).await;
+            }
+
+            let failure = match attempt_request.send().await {
+                Ok(response) => match classify(response).await {
+                    Ok(json) => return Ok(json),
+                    Err(failure) => failure,
+                },
+                // a connection reset while the request or the body is in flight is as
+                // transient as a timeout
+                Err(err) if err.is_timeout() || err.is_connect() || err.is_request() || err.is_body() => {
+                    Failure::Transient(err.into(), None)
+                }
+                Err(err) => Failure::Fatal(err.into()),
+            };
+
+            let (err, retry_after) = match failure {
+                Failure::Fatal(err) => return Err(err),
+                Failure::Transient(err, retry_after) => (err, retry_after),
+            };
+            if attempt >= self.settings.max_attempts {
+                return Err(err);
+            }
+
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            warn!(
+                "attempt {} of {} failed: {}, retrying in {:?}",
+                attempt, self.settings.max_attempts, err, delay
+            );
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        Backoff::from_config(&self.settings).delay(attempt)
+    }
+}
+
+/// Delays between the attempts of a retried request, the uploads to the storage backends
+/// back off the same way as the api calls.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Backoff {
+    pub fn from_config(settings: &HttpConfig) -> Self {
+        Backoff {
+            base_delay_ms: settings.base_delay_ms,
+            max_delay_ms: settings.max_delay_ms,
+        }
+    }
+
+    /// Random delay between zero and the exponential backoff of `attempt` ("full jitter").
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)));
+        let cap = exponential.min(self.max_delay_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+    }
+}
+
+async fn classify(response: Response) -> Result<JsonResponse, Failure> {
+    let status = response.status();
+    let url = redact(response.url());
+    let headers = response.headers().clone();
+    let retry_after = retry_after(&headers);
+    let body = response
+        .text()
+        .await
+        .map_err(|err| Failure::Transient(err.into(), None))?;
+
+    if status.is_success() {
+        let body = serde_json::from_str(&body).map_err(|err| {
+            Failure::Fatal(JobError::Parse(format!(
+                "invalid json from {}: {}: {}",
+                url,
+                err,
+                truncate(&body)
+            )))
+        })?;
+        return Ok(JsonResponse { body, headers });
+    }
+
+    let code = status.as_u16();
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Failure::Fatal(JobError::Auth {
+            status: code,
+            body: truncate(&body),
+        })),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::REQUEST_TIMEOUT => Err(Failure::Transient(
+            JobError::Http(format!("{} from {}: {}", code, url, truncate(&body))),
+            retry_after,
+        )),
+        status if status.is_server_error() => Err(Failure::Transient(
+            JobError::Http(format!("{} from {}: {}", code, url, truncate(&body))),
+            retry_after,
+        )),
+        _ => Err(Failure::Fatal(JobError::Client {
+            status: code,
+            body: truncate(&body),
+        })),
+    }
+}
+
+/// `Retry-After` as either delay seconds or an http date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// `url` without its query string and credentials, for logs and errors: query auth puts the
+/// api key in the query.
+pub fn redact(url: &reqwest::Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.to_string()
+}
+
+fn truncate(body: &str) -> String {
+    body.chars().take(MAX_BODY_CHARS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    fn response(status: u16, retry_after: Option<&str>, body: &str) -> Response {
+        let mut builder = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            builder = builder.header(RETRY_AFTER, retry_after);
+        }
+        Response::from(builder.body(body.to_string()).unwrap())
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_as_a_date() {
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let delay = retry_after(&headers(&date)).unwrap();
+        assert!(delay > Duration::from_secs(80) && delay <= Duration::from_secs(90));
+
+        let http_date = (chrono::Utc::now() + chrono::Duration::seconds(90))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        assert!(retry_after(&headers(&http_date)).is_some());
+    }
+
+    #[test]
+    fn retry_after_in_the_past_or_unreadable_is_ignored() {
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn success_is_parsed_as_json() {
+        match classify(response(200, None, r#"{"records": [1]}"#)).await {
+            Ok(json) => assert_eq!(json.body["records"][0], 1),
+            Err(_) => panic!("expected a json body"),
+        }
+        assert!(matches!(
+            classify(response(200, None, "<html>")).await,
+            Err(Failure::Fatal(JobError::Parse(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_throttling_are_retried() {
+        assert!(matches!(
+            classify(response(503, None, "down")).await,
+            Err(Failure::Transient(JobError::Http(_), None))
+        ));
+        assert!(matches!(
+            classify(response(500, None, "boom")).await,
+            Err(Failure::Transient(JobError::Http(_), None))
+        ));
+        assert!(matches!(
+            classify(response(408, None, "")).await,
+            Err(Failure::Transient(JobError::Http(_), None))
+        ));
+        match classify(response(429, Some("7"), "slow down")).await {
+            Err(Failure::Transient(_, retry_after)) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(7)))
+            }
+            _ => panic!("expected a transient failure"),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_fatal() {
+        assert!(matches!(
+            classify(response(401, None, "no")).await,
+            Err(Failure::Fatal(JobError::Auth { status: 401, .. }))
+        ));
+        assert!(matches!(
+            classify(response(403, None, "no")).await,
+            Err(Failure::Fatal(JobError::Auth { status: 403, .. }))
+        ));
+        assert!(matches!(
+            classify(response(404, None, "missing")).await,
+            Err(Failure::Fatal(JobError::Client { status: 404, .. }))
+        ));
+        assert!(matches!(
+            classify(response(422, Some("5"), "bad offset")).await,
+            Err(Failure::Fatal(JobError::Client { status: 422, .. }))
+        ));
+    }
+
+    /// Serves `responses` on a local port, one connection each. `None` drops the
+    /// connection once the request is read.
+    async fn serve(responses: Vec<Option<(u16, &'static str)>>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/items", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                let _ = socket.read(&mut request).await;
+                if let Some((status, body)) = response {
+                    let reply = format!(
+                        "HTTP/1.1 {} X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(reply.as_bytes()).await;
+                }
+            }
+        });
+        url
+    }
+
+    fn client(max_attempts: u32) -> HttpClient {
+        HttpClient::new(&HttpConfig {
+            max_attempts,
+            base_delay_ms: 1,
+            max_delay_ms: 1,
+            request_timeout_secs: 5,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_are_retried() {
+        let url = serve(vec![None, Some((200, r#"{"ok": true}"#))]).await;
+        let http = client(2);
+
+        let json = http.send_json(http.get(&url), "test", None).await.unwrap();
+        assert_eq!(json.body["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_fail_once_attempts_run_out() {
+        let url = serve(vec![None]).await;
+        let http = client(1);
+
+        assert!(matches!(
+            http.send_json(http.get(&url), "test", None).await,
+            Err(JobError::Http(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_auth_is_kept_out_of_errors() {
+        let url = serve(vec![Some((500, "boom")), Some((200, "<html>")), None]).await;
+        let http = client(1);
+        let request = || http.get(&url).query(&[("api_key", "s3cr3t")]);
+
+        for _ in 0..3 {
+            let err = http.send_json(request(), "test", None).await.unwrap_err();
+            assert!(matches!(err, JobError::Http(_) | JobError::Parse(_)));
+            assert!(!err.to_string().contains("s3cr3t"), "{}", err);
+            assert!(err.to_string().contains("/items"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped_and_does_not_overflow() {
+        let backoff = Backoff {
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+        };
+        for attempt in [0, 1, 2, 10, 64, u32::MAX] {
+            assert!(backoff.delay(attempt) <= Duration::from_millis(30_000));
+        }
+        assert!(backoff.delay(1) <= Duration::from_millis(500));
+    }
+}
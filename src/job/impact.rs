@@ -1,165 +1,341 @@
 use super::{
-    config::{get_config, setup_campaigns},
-    job::{Impact, RestApi},
+    config::{check_file, setup_campaigns, SourceConfig},
+    error::JobError,
+    job::{join_units, Impact, RestApi, RunContext, Source},
+    manifest::ColumnSchema,
+    output::OutputFormat,
+    plan::PlanEntry,
+    registry::UnitFilters,
+    summary::{UnitReport, UnitStats},
 };
-use crate::job::job::{AwsS3, StoragePlatform, Tasks};
+use crate::job::job::Tasks;
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
 use polars::prelude::*;
 use polars_sql::SQLContext;
 use std::fs;
 use std::fs::File;
-use std::{collections::HashMap, env, io::Write, time::Duration as DurationStd};
-use tokio::time::timeout;
-use tracing::{error, info};
+use std::pin::pin;
+use std::{collections::HashMap, io::Write};
+use tracing::info;
+
+impl Impact {
+    pub const REPORTS: [&'static str; 2] = [
+        "adv_action_listing_pm_only",
+        "partner_funds_transfer_listing_",
+    ];
+
+    /// Filters the units of work can be narrowed down by.
+    pub const FILTERS: &'static [&'static str] = &["campaign"];
+
+    pub fn build(
+        name: &str,
+        config: &SourceConfig,
+        context: RunContext,
+        filters: &UnitFilters,
+    ) -> Result<Box<dyn Source>, JobError> {
+        Ok(Box::new(Impact {
+            context,
+            name: name.to_string(),
+            settings: config.settings(name)?,
+            execution_date: String::new(),
+            api_url: String::new(),
+            report: String::new(),
+            parameters: String::new(),
+            auth_sid: String::new(),
+            auth_token: String::new(),
+            sub_account_name: String::new(),
+            key: String::new(),
+            campaign_filter: filters.get("campaign").cloned(),
+        }))
+    }
+
+    /// Campaign keys to extract, narrowed down by the campaign filter.
+    pub fn selected_campaigns(
+        &self,
+        campaign: &HashMap<String, Vec<String>>,
+    ) -> Result<Vec<String>, JobError> {
+        let mut keys: Vec<String> = campaign.keys().cloned().collect();
+
+        if let Some(key) = &self.campaign_filter {
+            keys.retain(|name| name == key);
+            if keys.is_empty() {
+                return Err(JobError::Config(format!(
+                    "impact campaign {} is not defined in the campaigns file",
+                    key
+                )));
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Copy of the campaign job in `key` set up to request a single report.
+    pub fn for_report(
+        &self,
+        report: &str,
+        campaign: &HashMap<String, Vec<String>>,
+    ) -> Result<Impact, JobError> {
+        let key = self.key.as_str();
+        let campaign_detail = campaign
+            .get(key)
+            .ok_or_else(|| {
+                JobError::Config(format!(
+                    "impact campaign {} is not defined in the campaigns file",
+                    key
+                ))
+            })?;
+        let [currency, sub_account_name, ..] = campaign_detail.as_slice() else {
+            return Err(JobError::Config(format!(
+                "impact campaign {} needs a currency and a sub account name",
+                key
+            )));
+        };
+        let account = self.settings.accounts.get(key).ok_or_else(|| {
+            JobError::Config(format!("no impact account for {} in {}.accounts", key, self.name))
+        })?;
+        let auth_sid = account.sid.as_str();
+        let auth_token = account.auth_token.as_str();
+
+        let api_url = format!("https://api.impact.com/Advertisers/{}/Reports/", auth_sid);
+        let parameters = if report == "adv_action_listing_pm_only" {
+            format!(
+                    "&subaid={}&CONV_CURRENCY={}&SHOW_DATE=1&SHOW_GROUP=1&SHOW_ACTION_BATCH_DATE=1&SHOW_ACTION_BATCH_ID=1&SHOW_AD=1&SHOW_AD_PLACEMENT=1&SHOW_AD_POSITION=1&SHOW_AD_RANK=1&SHOW_BONUS_COST=1&SHOW_BUSINESS_REGION=1&SHOW_MP_BUSINESS_REGION=1&SHOW_ACTION_CATEGORY=1&SHOW_SUBCATEGORY=1&SHOW_CLIENT_COST=1&SHOW_CHANNEL=1&SHOW_PROPERTY_ID=1&SHOW_PROPERTY_NAME=1&SHOW_USER_AGENT2=1&SHOW_ACTUAL_CLEARING_DATE=1&SHOW_GEO_LOCATION=1&SHOW_LOCATION_NAME=1&SHOW_CLICK_TO_ACTION=1&SHOW_LOCATION_ID=1&SHOW_LOCATION_TYPE=1&SHOW_MP_VALUE1=1&SHOW_MP_VALUE2=1&SHOW_MP_VALUE3=1&SHOW_PROPERTY_TYPE=1&SHOW_TEXT3=1&SHOW_TEXT2=1&SHOW_TEXT1=1&SHOW_STATUS_DETAIL=1&SHOW_REFERRING_URL=1&SHOW_RELATIONSHIP=1&SHOW_SCHEDULED_CLEARING_DATE=1&SHOW_SHIPPING=1&SHOW_SITE_CATEGORY=1&SHOW_SITE_VERSION=1&SHOW_PARAM1=1&SHOW_PARAM2=1&SHOW_PARAM3=1&SHOW_MP__PROPERTY=1&SHOW_REFERRAL_TRAFFIC_SOURCE=1&SHOW_REFERRAL_TRAFFIC_TYPE=1&SHOW_REFERRAL_TYPE=1&SHOW_NOTES=1&SHOW_PROMO_DESCRIPTION=1&SHOW_ORIGINAL_PAYOUT2=1&SHOW_ORIGINAL_SALEAMOUNT=1&SHOW_PAYMENT_TYPE=1&SHOW_POST_CODE=1&SHOW_PROPERTY=1&SHOW_REBATE=1&SHOW_REDIRECT_RULE_ID=1&SHOW_REDIRECT_RULE=1&SHOW_REFERRAL_DATE=1&SHOW_MODIFICATION_REASON=1&SHOW_MONEY1=1&SHOW_MONEY2=1&SHOW_MONEY3=1&SHOW_NUMERIC1=1&SHOW_NUMERIC2=1&SHOW_NUMERIC3=1&SHOW_DISPOSITION=1&SHOW_HEAR_ABOUT=1&SHOW_LANDING_PAGE=1&SHOW_LINE_BUSINESS=1&SHOW_MP_LINE_BUSINESS=1&SHOW_MODIFICATION_DATE=1&SHOW_LOCKING_DATE=1&SHOW_SUBTOTAL=1&SHOW_ADV_CUST_REGION=1&SHOW_CUST_COUNTRY=1&SHOW_ADV_CUST_CITY=1&SHOW_CUST_EMAIL=1&SHOW_CUSTOMER_ID=1&SHOW_CUSTOMER_STATUS=1&SHOW_CUSTDATE2=1&SHOW_CUSTDATE1=1&SHOW_CUSTDATE3=1&SHOW_DISCOUNT=1&SHOW_IO=1&SHOW_CURRENCY_CONV=1&SUPERSTATUS_MS=APPROVED&SUPERSTATUS_MS=NA&SUPERSTATUS_MS=PENDING&SUPERSTATUS_MS=REVERSED",
+                    self.key, currency
+                )
+        } else {
+            format!(
+                    "&subaid={}&ADV_CAMPAIGN=0&INITIATED_BY=0&RECIPIENT_ACCOUNT_ID=0&PARTNER_RADIUS_SOLR=0&MP_GROUP_ADV=0",self.key
+                )
+        };
+
+        let mut impact_extraction = self.clone();
+        impact_extraction.sub_account_name = sub_account_name.to_string();
+        impact_extraction.api_url = api_url.replace("\"", "");
+        impact_extraction.auth_token = auth_token.replace("\"", "");
+        impact_extraction.execution_date = self.context.execution_date.replace("\"", "");
+        impact_extraction.parameters = parameters.replace("\"", "");
+        impact_extraction.report = report.replace("\"", "");
+        impact_extraction.auth_sid = auth_sid.replace("\"", "");
+
+        Ok(impact_extraction)
+    }
+
+    /// Local files the unit of `key`/`report` writes, known before the unit resolves its account.
+    pub fn partial_files(&self) -> Vec<String> {
+        let mut unit = self.clone();
+        if let Some(account) = self.settings.accounts.get(&self.key) {
+            unit.auth_sid = account.sid.replace("\"", "");
+        }
+        let mut files = vec![self.context.local_path(self.source(), &unit.output_file())];
+        files.extend(self.context.result_paths(
+            self.source(),
+            &unit.result_file(),
+            unit.output_format(),
+        ));
+        files
+    }
+
+    pub fn output_file(&self) -> String {
+        format!("{}_{}_impact.json", self.report, self.auth_sid)
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.context.output_format(self.source(), &self.report)
+    }
+
+    pub fn result_file(&self) -> String {
+        format!(
+            "result_{}_{}_impact.{}",
+            self.report,
+            self.auth_sid,
+            self.output_format().extension()
+        )
+    }
+}
 
 impl Tasks for Impact {
+    fn source(&self) -> &str {
+        &self.name
+    }
+
     #[tracing::instrument(err, skip_all)]
-    async fn extraction(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut file = File::create(format!("{}_{}_impact.json", self.report, self.auth_sid))?;
-        let data = self.fetch_sync().await?;
-
-        let datas = data.get("Records");
-
-        match datas {
-            Some(data) => {
-                let arr_data = data.as_array().unwrap();
-                for d in arr_data {
-                    serde_json::to_writer(&mut file, &d)?;
-                    match writeln!(&mut file) {
-                        Ok(()) => continue,
-                        Err(err) => error!(
-                            "error write json data into {}_{}_impact.json\nMessage: {:?}",
-                            self.report, self.auth_sid, err
-                        ),
-                    }
-                }
-            }
-            None => {
-                error!("impact empty data with error: :{:?}", data)
+    async fn extraction(&mut self) -> Result<usize, JobError> {
+        let mut file =
+            File::create(self.context.output_path(self.source(), &self.output_file())?)?;
+        let http = self.context.http.clone();
+        let mut pages = 0;
+        let mut records = 0;
+
+        let mut stream = pin!(self.pages(&http));
+        while let Some(page) = stream.try_next().await? {
+            let data = self.records(&page.body)?;
+            for d in data {
+                serde_json::to_writer(&mut file, &d)?;
+                writeln!(&mut file)?;
             }
+            pages = page.number;
+            records += data.len();
         }
 
-        Ok(())
+        info!(
+            "extracted {} records of {} for {} in {} page(s)",
+            records, self.report, self.key, pages
+        );
+        Ok(records)
     }
 
     #[tracing::instrument(err, skip_all)]
-    async fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let campaign: HashMap<String, Vec<String>> = setup_campaigns().await?;
-        self.sub_account_name = campaign[self.key.as_str()][1].to_string();
-
-        let config = get_config().await?;
-        let execution_date = env::var("CURRENT_DATE").expect("$PIPELINE_CONFIG is not set");
-        let impact_acc_sid_v2 = &config["impact_acc_sid_v2"];
-        let impact_auth_token_v2 = &config["impact_auth_token_v2"];
-        let query_path = config["impact_queries_path"].as_str().unwrap();
-        let key = self.key.as_str();
+    async fn execute(&mut self) -> Result<UnitStats, JobError> {
+        let campaign: HashMap<String, Vec<String>> =
+            setup_campaigns(&self.context.config, &self.settings).await?;
+        let query_path = self.context.config.paths.impact_queries.as_str();
+
+        let mut impact_extraction = self.for_report(&self.report, &campaign)?;
+        impact_extraction.extraction().await?;
 
-        let api_url = format!(
-            "https://api.impact.com/Advertisers/{}/Reports/",
-            impact_acc_sid_v2.get(key).unwrap().as_str().unwrap()
+        let report = impact_extraction.report.as_str();
+        let auth_sid = impact_extraction.auth_sid.as_str();
+        let mut file = std::fs::File::open(
+            self.context
+                .output_path(self.source(), &impact_extraction.output_file())?,
+        )?;
+        let df = JsonLineReader::new(&mut file).finish()?;
+
+        let mut ctx = SQLContext::new();
+        ctx.register(
+            format!("df_{}_{}", report, auth_sid).as_str(),
+            df.clone().lazy(),
         );
 
-        let auth_sid = impact_acc_sid_v2.get(key).unwrap().as_str().unwrap();
-        let auth_token = impact_auth_token_v2.get(key).unwrap().as_str().unwrap();
-
-        let strings = vec![
-            "adv_action_listing_pm_only",
-            "partner_funds_transfer_listing_",
-        ];
-
-        for s in strings {
-            let mut impact_extraction = self.clone();
-            impact_extraction.api_url = api_url.to_string().replace("\"", "");
-            impact_extraction.auth_token = auth_token.to_string().replace("\"", "");
-            impact_extraction.execution_date = execution_date.to_string().replace("\"", "");
-            let parameters = if s == "adv_action_listing_pm_only" {
-                format!(
-                        "&subaid={}&CONV_CURRENCY={}&SHOW_DATE=1&SHOW_GROUP=1&SHOW_ACTION_BATCH_DATE=1&SHOW_ACTION_BATCH_ID=1&SHOW_AD=1&SHOW_AD_PLACEMENT=1&SHOW_AD_POSITION=1&SHOW_AD_RANK=1&SHOW_BONUS_COST=1&SHOW_BUSINESS_REGION=1&SHOW_MP_BUSINESS_REGION=1&SHOW_ACTION_CATEGORY=1&SHOW_SUBCATEGORY=1&SHOW_CLIENT_COST=1&SHOW_CHANNEL=1&SHOW_PROPERTY_ID=1&SHOW_PROPERTY_NAME=1&SHOW_USER_AGENT2=1&SHOW_ACTUAL_CLEARING_DATE=1&SHOW_GEO_LOCATION=1&SHOW_LOCATION_NAME=1&SHOW_CLICK_TO_ACTION=1&SHOW_LOCATION_ID=1&SHOW_LOCATION_TYPE=1&SHOW_MP_VALUE1=1&SHOW_MP_VALUE2=1&SHOW_MP_VALUE3=1&SHOW_PROPERTY_TYPE=1&SHOW_TEXT3=1&SHOW_TEXT2=1&SHOW_TEXT1=1&SHOW_STATUS_DETAIL=1&SHOW_REFERRING_URL=1&SHOW_RELATIONSHIP=1&SHOW_SCHEDULED_CLEARING_DATE=1&SHOW_SHIPPING=1&SHOW_SITE_CATEGORY=1&SHOW_SITE_VERSION=1&SHOW_PARAM1=1&SHOW_PARAM2=1&SHOW_PARAM3=1&SHOW_MP__PROPERTY=1&SHOW_REFERRAL_TRAFFIC_SOURCE=1&SHOW_REFERRAL_TRAFFIC_TYPE=1&SHOW_REFERRAL_TYPE=1&SHOW_NOTES=1&SHOW_PROMO_DESCRIPTION=1&SHOW_ORIGINAL_PAYOUT2=1&SHOW_ORIGINAL_SALEAMOUNT=1&SHOW_PAYMENT_TYPE=1&SHOW_POST_CODE=1&SHOW_PROPERTY=1&SHOW_REBATE=1&SHOW_REDIRECT_RULE_ID=1&SHOW_REDIRECT_RULE=1&SHOW_REFERRAL_DATE=1&SHOW_MODIFICATION_REASON=1&SHOW_MONEY1=1&SHOW_MONEY2=1&SHOW_MONEY3=1&SHOW_NUMERIC1=1&SHOW_NUMERIC2=1&SHOW_NUMERIC3=1&SHOW_DISPOSITION=1&SHOW_HEAR_ABOUT=1&SHOW_LANDING_PAGE=1&SHOW_LINE_BUSINESS=1&SHOW_MP_LINE_BUSINESS=1&SHOW_MODIFICATION_DATE=1&SHOW_LOCKING_DATE=1&SHOW_SUBTOTAL=1&SHOW_ADV_CUST_REGION=1&SHOW_CUST_COUNTRY=1&SHOW_ADV_CUST_CITY=1&SHOW_CUST_EMAIL=1&SHOW_CUSTOMER_ID=1&SHOW_CUSTOMER_STATUS=1&SHOW_CUSTDATE2=1&SHOW_CUSTDATE1=1&SHOW_CUSTDATE3=1&SHOW_DISCOUNT=1&SHOW_IO=1&SHOW_CURRENCY_CONV=1&SUPERSTATUS_MS=APPROVED&SUPERSTATUS_MS=NA&SUPERSTATUS_MS=PENDING&SUPERSTATUS_MS=REVERSED",
-                        self.key, campaign[self.key.as_str()][0]
-                    )
-            } else {
-                format!(
-                        "&subaid={}&ADV_CAMPAIGN=0&INITIATED_BY=0&RECIPIENT_ACCOUNT_ID=0&PARTNER_RADIUS_SOLR=0&MP_GROUP_ADV=0",self.key
-                    )
-            };
-            impact_extraction.parameters = parameters.replace("\"", "");
-            impact_extraction.report = s.to_string().replace("\"", "");
-            impact_extraction.auth_sid = auth_sid.to_string().replace("\"", "");
-
-            impact_extraction.extraction().await?;
-
-            let report = s;
-            let mut file = std::fs::File::open(format!("{}_{}_impact.json", report, auth_sid))?;
-            let df = JsonLineReader::new(&mut file).finish()?;
-            let mut sql_df: DataFrame = DataFrame::empty();
-
-            let mut ctx = SQLContext::new();
-            ctx.register(
-                format!("df_{}_{}", report, auth_sid).as_str(),
-                df.clone().lazy(),
-            );
-
-            let full_path = format!("{}/{}.sql", query_path, report);
-            let query_template = fs::read_to_string(full_path).unwrap_or_else(|_| String::new());
-            let query = format!(
-                "{}",
-                query_template
-                    .replace("{sub_account_name}", &self.sub_account_name)
-                    .replace("{auth_sid}", auth_sid)
-                    .replace("{report}", report)
-            );
-
-            sql_df = ctx.execute(query.as_str()).unwrap().collect().unwrap();
-
-            let file_name = format!("result_{}_{}_impact.csv", report, auth_sid);
-            let mut file = std::fs::File::create(&file_name)?;
-
-            match CsvWriter::new(&mut file).finish(&mut sql_df) {
-                Ok(()) => {
-                    info!("success write result_{}_{}_impact.csv", report, auth_sid);
-                    StoragePlatform::upload(
-                        AwsS3 {
-                            config: None,
-                            client: None,
-                            bucket_name: None,
-                        },
-                        file_name,
-                    )
-                    .await?;
-                }
-                Err(e) => error!(
-                    "error writing result_{}_{}_impact.csv\n Message:{:?}",
-                    report, auth_sid, e
-                ),
-            }
-        }
+        let full_path = format!("{}/{}.sql", query_path, report);
+        let query_template = fs::read_to_string(&full_path).map_err(|err| {
+            JobError::Config(format!("could not read impact query {}: {}", full_path, err))
+        })?;
+        let query = query_template
+            .replace("{sub_account_name}", &impact_extraction.sub_account_name)
+            .replace("{auth_sid}", auth_sid)
+            .replace("{report}", report);
+
+        let mut sql_df = ctx.execute(query.as_str())?.collect()?;
+
+        let format = impact_extraction.output_format();
+        let result_file = impact_extraction.result_file();
+        let file_name = self.context.output_path(self.source(), &result_file)?;
+        format.write(&mut sql_df, &file_name, self.context.config.output.compression)?;
+        info!("success write {}", file_name);
 
-        Ok(())
+        let object = self.context.output_object(
+            self.source(),
+            &impact_extraction.report,
+            &result_file,
+            format,
+        )
+        .await?;
+        let mut stats =
+            UnitStats::for_object(&object, sql_df.height(), ColumnSchema::of_frame(&sql_df))?;
+        stats.destinations = self.context.upload(self.source(), &impact_extraction.report, &object).await?;
+        Ok(stats)
     }
+}
 
-    #[tracing::instrument(err)]
-    async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let campaign: Vec<String> = setup_campaigns().await?.keys().cloned().collect();
+impl Impact {
+    #[tracing::instrument(err, skip_all, fields(source = %self.name))]
+    async fn run_units(&self) -> Result<Vec<UnitReport>, JobError> {
+        let campaign: Vec<String> = self
+            .selected_campaigns(&setup_campaigns(&self.context.config, &self.settings).await?)?;
 
         let handles: Vec<_> = campaign
             .into_iter()
-            .map(|key| {
+            .flat_map(|key| Impact::REPORTS.map(|report| (key.clone(), report)))
+            .map(|(key, report)| {
                 let mut impact_clone = self.clone();
                 impact_clone.key = key;
-                tokio::spawn(async move {
-                    if let Err(err) =
-                        timeout(DurationStd::from_secs(3600), impact_clone.execute()).await
-                    {
-                        eprintln!("Error executing task: {}", err);
-                    }
-                })
+                impact_clone.report = report.to_string();
+                let unit = format!("{}/{}", impact_clone.key, report);
+                let handle = self.context.spawn_unit(
+                    self.source(),
+                    &unit,
+                    impact_clone.partial_files(),
+                    async move { impact_clone.execute().await },
+                );
+                (unit, handle)
             })
             .collect();
 
-        // Wait for all tasks to complete
-        for handle in handles {
-            handle.await.expect("Failed to join task");
+        Ok(join_units(&self.context, self.source(), handles).await)
+    }
+
+    #[tracing::instrument(err, skip_all)]
+    async fn plan_units(&self) -> Result<Vec<PlanEntry>, JobError> {
+        let campaign: HashMap<String, Vec<String>> =
+            setup_campaigns(&self.context.config, &self.settings).await?;
+
+        let mut entries = Vec::new();
+        for key in self.selected_campaigns(&campaign)? {
+            let mut impact_clone = self.clone();
+            impact_clone.key = key;
+            for report in Impact::REPORTS {
+                let impact_extraction = impact_clone.for_report(report, &campaign)?;
+                entries.push(PlanEntry {
+                    execution_date: self.context.execution_date.clone(),
+                    source: self.source().to_string(),
+                    key: format!("{}/{}", impact_extraction.key, report),
+                    request: impact_extraction.report_url()?,
+                    s3_key: self.context.upload_key(
+                        self.source(),
+                        report,
+                        &impact_extraction.result_file(),
+                        impact_extraction.output_format(),
+                    ),
+                });
+            }
         }
 
-        Ok(())
+        Ok(entries)
+    }
+}
+
+impl Source for Impact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<Vec<UnitReport>, JobError>> {
+        Box::pin(self.run_units())
+    }
+
+    fn plan(&self) -> BoxFuture<'_, Result<Vec<PlanEntry>, JobError>> {
+        Box::pin(self.plan_units())
+    }
+
+    fn validate(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut problems = Vec::new();
+            let paths = &self.context.config.paths;
+            for report in Impact::REPORTS {
+                check_file(&format!("{}/{}.sql", paths.impact_queries, report), &mut problems);
+            }
+
+            match setup_campaigns(&self.context.config, &self.settings).await {
+                Ok(campaign) => {
+                    for key in campaign.keys() {
+                        if !self.settings.accounts.contains_key(key) {
+                            problems.push(format!(
+                                "impact campaign {} has no account in {}.accounts",
+                                key, self.name
+                            ));
+                        }
+                    }
+                }
+                Err(err) => problems.push(err.to_string()),
+            }
+
+            problems
+        })
+    }
+
+    fn filtered(&self) -> bool {
+        self.campaign_filter.is_some()
     }
 }
@@ -1,38 +1,159 @@
-use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::config::{Credentials, RequestChecksumCalculation};
 use aws_sdk_s3::Client;
-use std::env;
+use futures::future::BoxFuture;
+use futures::Stream;
+use reqwest::{RequestBuilder, Url};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
-use super::config::get_config;
+use super::checkpoint::CheckpointStore;
+use super::config::{
+    AirtableConfig, AwsConfig, EmarsysConfig, GcsConfig, ImpactConfig, LocalStorageConfig,
+    Pagination, PipelineConfig, RateLimit, RestConfig, StorageKind, UploadPolicy,
+};
+use super::error::JobError;
+use super::http::{Backoff, HttpClient};
+use super::manifest::Manifest;
+use super::output::OutputFormat;
+use super::pagination::{self, Page};
+use super::plan::PlanEntry;
+use super::scheduler::{Scheduler, UnitHandle};
+use super::summary::{DestinationReport, UnitReport, UnitStats};
+use super::utility;
 
 pub trait Tasks {
-    async fn extraction(&mut self) -> Result<(), Box<dyn std::error::Error>>;
-    async fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>>;
-    async fn run(&self) -> Result<(), Box<dyn std::error::Error>>;
+    /// Name of the source instance.
+    fn source(&self) -> &str;
+    /// Extracts the raw records of one unit of work, returns how many were extracted.
+    async fn extraction(&mut self) -> Result<usize, JobError>;
+    async fn execute(&mut self) -> Result<UnitStats, JobError>;
+}
+
+/// A source instance declared in the config, built by the source registry. Object safe so
+/// that instances of any type can be run side by side.
+pub trait Source: Send + Sync {
+    fn name(&self) -> &str;
+    /// Runs every unit of work of the source. An `Err` means the source could not even
+    /// list its units, failures of single units are reported in the returned results.
+    fn run(&self) -> BoxFuture<'_, Result<Vec<UnitReport>, JobError>>;
+    fn plan(&self) -> BoxFuture<'_, Result<Vec<PlanEntry>, JobError>>;
+    /// Problems of the settings of the source and of the files they reference.
+    fn validate(&self) -> BoxFuture<'_, Vec<String>>;
+    /// Whether a filter limits the run to some of the units of work, such a run writes
+    /// no manifest.
+    fn filtered(&self) -> bool;
 }
 
 pub trait Storage {
-    async fn init(&mut self) -> Result<(), Box<dyn std::error::Error>>;
-    async fn upload(&self, filename: String) -> Result<(), Box<dyn std::error::Error>>;
+    async fn init(&mut self) -> Result<(), JobError>;
+    /// Uploads the file and checks the stored object against `object.sha256`.
+    async fn upload(&self, object: &UploadObject) -> Result<(), JobError>;
+}
+
+/// A local file and the object it is stored as.
+#[derive(Clone, Debug)]
+pub struct UploadObject {
+    pub local_path: String,
+    pub key: String,
+    pub content_type: String,
+    pub content_encoding: Option<String>,
+    /// Hex encoded SHA-256 of the file.
+    pub sha256: String,
+}
+
+impl UploadObject {
+    /// Hashes the file on the blocking pool, result files can be large.
+    pub async fn new(
+        local_path: &str,
+        key: &str,
+        content_type: &str,
+        content_encoding: Option<&str>,
+    ) -> Result<Self, JobError> {
+        let path = local_path.to_string();
+        let sha256 = tokio::task::spawn_blocking(move || utility::sha256_file(&path))
+            .await
+            .map_err(|err| JobError::Task(err.to_string()))??;
+        Ok(UploadObject {
+            local_path: local_path.to_string(),
+            key: key.to_string(),
+            content_type: content_type.to_string(),
+            content_encoding: content_encoding.map(String::from),
+            sha256,
+        })
+    }
 }
 
+/// A json api read page by page, every source iterates its pages through `pages`.
 pub trait RestApi {
-    async fn fetch_sync(&mut self) -> Result<serde_json::Value, Box<dyn std::error::Error>>;
+    fn first_page(&self) -> Result<Url, JobError>;
+    /// Request of the page at `url`, auth and headers included.
+    fn request(&self, http: &HttpClient, url: &Url) -> RequestBuilder;
+    /// Bucket the requests are rate limited in and its limit.
+    fn rate_limit(&self) -> (String, Option<&RateLimit>);
+    fn pagination(&self) -> Pagination;
+    /// Records array of the json body of a page.
+    fn records<'a>(
+        &self,
+        body: &'a serde_json::Value,
+    ) -> Result<&'a Vec<serde_json::Value>, JobError>;
+
+    /// Every page from the first one, following `pagination`.
+    fn pages<'a>(
+        &'a self,
+        http: &'a HttpClient,
+    ) -> impl Stream<Item = Result<Page, JobError>> + 'a
+    where
+        Self: Sized,
+    {
+        pagination::pages(self, http)
+    }
 }
 
 pub struct StoragePlatform;
 
+#[derive(Clone)]
+pub struct RunContext {
+    pub config: Arc<PipelineConfig>,
+    pub run_id: String,
+    pub execution_date: String,
+    pub scheduler: Scheduler,
+    pub http: HttpClient,
+    pub checkpoints: CheckpointStore,
+    pub storage: StorageRegistry,
+    /// Keeps the local files of the units of work once they are uploaded.
+    pub keep_artifacts: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct Airtable {
+    pub context: RunContext,
+    pub name: String,
+    pub settings: AirtableConfig,
     pub job_details: AtJobDetail,
+    pub endpoint_filter: Option<String>,
+    pub year_filter: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct EmarsysBq {
+    pub context: RunContext,
+    pub name: String,
+    pub settings: EmarsysConfig,
     pub table_name: String,
+    pub table_filter: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Impact {
+    pub context: RunContext,
+    pub name: String,
+    pub settings: ImpactConfig,
     pub execution_date: String,
     pub api_url: String,
     pub report: String,
@@ -41,6 +162,17 @@ pub struct Impact {
     pub auth_token: String,
     pub sub_account_name: String,
     pub key: String,
+    pub campaign_filter: Option<String>,
+}
+
+/// A source driven by its config alone, see `RestConfig`.
+#[derive(Clone, Debug)]
+pub struct Rest {
+    pub context: RunContext,
+    pub name: String,
+    pub settings: RestConfig,
+    pub table_name: String,
+    pub table_filter: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -51,43 +183,454 @@ pub struct AtJobDetail {
     pub api_endpoint: String,
     pub airtable_url: String,
     pub auth_token: String,
+    /// Offset of the first page, set when a run resumes the table.
     pub offset_value: String,
+    pub rate_limit: Option<RateLimit>,
 }
 
 #[derive(Clone, Debug)]
 pub struct AwsS3 {
-    pub config: Option<SdkConfig>,
+    /// Destination name, `s3` for the `aws` bucket.
+    pub name: String,
+    pub settings: AwsConfig,
+    /// Delays between the attempts of a part.
+    pub backoff: Backoff,
     pub client: Option<Client>,
-    pub bucket_name: Option<String>,
+}
+
+/// Stores the objects as files under a local directory.
+#[derive(Clone, Debug)]
+pub struct LocalFs {
+    pub dir: String,
+}
+
+/// Google cloud storage bucket, uploaded to with resumable uploads.
+#[derive(Clone)]
+pub struct Gcs {
+    pub settings: GcsConfig,
+    /// Delays between the attempts of a chunk.
+    pub backoff: Backoff,
+    pub client: Option<google_cloud_storage::client::Client>,
+    /// Asks gcs how much of an upload it holds, the session url is its own authorization.
+    pub http: reqwest::Client,
+}
+
+/// A storage backend a source uploads to, picked with `storage` in the config.
+#[derive(Clone, Debug)]
+pub enum StorageBackend {
+    S3(Box<AwsS3>),
+    Local(LocalFs),
+    Gcs(Box<Gcs>),
+}
+
+impl StorageBackend {
+    pub fn from_config(config: &PipelineConfig, kind: &StorageKind) -> Result<Self, JobError> {
+        match kind {
+            StorageKind::S3 | StorageKind::S3Named(_) => {
+                let settings = config.s3_settings(kind).ok_or_else(|| match kind {
+                    StorageKind::S3 => {
+                        JobError::Config("the s3 storage backend needs the aws section".to_string())
+                    }
+                    _ => JobError::Config(format!("storage.s3 has no bucket {}", kind.name())),
+                })?;
+                let backoff = Backoff::from_config(&config.http);
+                Ok(StorageBackend::S3(Box::new(AwsS3::from_config(kind.name(), settings, backoff))))
+            }
+            StorageKind::Local => {
+                let LocalStorageConfig { dir } = config.storage.local.as_ref().ok_or_else(|| {
+                    JobError::Config("the local storage backend needs storage.local.dir".to_string())
+                })?;
+                Ok(StorageBackend::Local(LocalFs { dir: dir.clone() }))
+            }
+            StorageKind::Gcs => {
+                let settings = config.storage.gcs.clone().ok_or_else(|| {
+                    JobError::Config("the gcs storage backend needs storage.gcs".to_string())
+                })?;
+                Ok(StorageBackend::Gcs(Box::new(Gcs {
+                    settings,
+                    backoff: Backoff::from_config(&config.http),
+                    client: None,
+                    http: reqwest::Client::new(),
+                })))
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            StorageBackend::S3(storage) => &storage.name,
+            StorageBackend::Local(_) => StorageKind::Local.name(),
+            StorageBackend::Gcs(_) => StorageKind::Gcs.name(),
+        }
+    }
+}
+
+/// The storage backends of a run, connected once and shared by every upload.
+#[derive(Clone, Debug, Default)]
+pub struct StorageRegistry {
+    backends: HashMap<StorageKind, Arc<StorageBackend>>,
+}
+
+impl StorageRegistry {
+    /// Connects the backends `kinds`, those of the sources a run selected, so a backend
+    /// none of them uploads to needs no credentials.
+    pub async fn connect(config: &PipelineConfig, kinds: &[StorageKind]) -> Result<Self, JobError> {
+        let mut backends = HashMap::new();
+        for kind in kinds {
+            if backends.contains_key(kind) {
+                continue;
+            }
+            let mut backend = StorageBackend::from_config(config, kind)?;
+            backend.init().await?;
+            backends.insert(kind.clone(), Arc::new(backend));
+        }
+
+        Ok(StorageRegistry { backends })
+    }
+
+    pub fn get(&self, kinds: &[StorageKind]) -> Result<Vec<Arc<StorageBackend>>, JobError> {
+        kinds
+            .iter()
+            .map(|kind| {
+                self.backends.get(kind).cloned().ok_or_else(|| {
+                    JobError::Config(format!("storage backend {} is not connected", kind.name()))
+                })
+            })
+            .collect()
+    }
+
+    /// The s3 backend `kind`, for the uploads that need more than `Storage`.
+    pub fn s3(&self, kind: &StorageKind) -> Result<&AwsS3, JobError> {
+        match self.backends.get(kind).map(Arc::as_ref) {
+            Some(StorageBackend::S3(s3)) => Ok(s3),
+            _ => Err(JobError::Config(format!(
+                "s3 storage backend {} is not connected",
+                kind.name()
+            ))),
+        }
+    }
+}
+
+impl Storage for StorageBackend {
+    async fn init(&mut self) -> Result<(), JobError> {
+        match self {
+            StorageBackend::S3(storage) => storage.init().await,
+            StorageBackend::Local(storage) => storage.init().await,
+            StorageBackend::Gcs(storage) => storage.init().await,
+        }
+    }
+
+    async fn upload(&self, object: &UploadObject) -> Result<(), JobError> {
+        match self {
+            StorageBackend::S3(storage) => storage.upload(object).await,
+            StorageBackend::Local(storage) => storage.upload(object).await,
+            StorageBackend::Gcs(storage) => storage.upload(object).await,
+        }
+    }
 }
 
 impl AwsS3 {
-    pub async fn new(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let yaml_config = get_config().await?;
+    pub fn from_config(name: &str, settings: &AwsConfig, backoff: Backoff) -> Self {
+        AwsS3 {
+            name: name.to_string(),
+            settings: settings.clone(),
+            backoff,
+            client: None,
+        }
+    }
 
-        env::set_var(
-            "AWS_ACCESS_KEY_ID",
-            &yaml_config["aws_access_key"].as_str().unwrap(),
-        );
-        env::set_var(
-            "AWS_SECRET_ACCESS_KEY",
-            &yaml_config["aws_secret_key"].as_str().unwrap(),
-        );
-        env::set_var("AWS_REGION", &yaml_config["aws_region"].as_str().unwrap());
+    /// Builds the client from the settings alone, the process environment is only read
+    /// by the standard credential chain.
+    pub async fn connect(&mut self) -> Result<(), JobError> {
+        let settings = &self.settings;
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &settings.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(profile) = &settings.profile {
+            loader = loader.profile_name(profile);
+        }
+        if let (Some(access_key), Some(secret_key)) = (&settings.access_key, &settings.secret_key) {
+            loader = loader.credentials_provider(Credentials::new(
+                access_key,
+                secret_key,
+                settings.session_token.clone(),
+                None,
+                "pipeline-config",
+            ));
+        }
+        let base = loader.load().await;
 
-        let region_provider = RegionProviderChain::default_provider();
-        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-            .region(region_provider)
-            .load()
-            .await;
-        let client = Client::new(&config);
-        let bucket_name = yaml_config["bucket_name"].as_str().unwrap().to_string();
+        // the default crc32 trailer sends bodies as aws-chunked, which replaces the
+        // content encoding of compressed files
+        let mut config = aws_sdk_s3::config::Builder::from(&base)
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired);
+        if let Some(role_arn) = &settings.role_arn {
+            let mut role = AssumeRoleProvider::builder(role_arn)
+                .session_name(
+                    settings
+                        .role_session_name
+                        .as_deref()
+                        .unwrap_or("rust-extraction"),
+                )
+                .configure(&base);
+            if let Some(external_id) = &settings.external_id {
+                role = role.external_id(external_id);
+            }
+            config = config.credentials_provider(role.build().await);
+        }
+        if let Some(endpoint_url) = &settings.endpoint_url {
+            config = config.endpoint_url(endpoint_url).force_path_style(true);
+        }
+
+        self.client = Some(Client::from_conf(config.build()));
+        Ok(())
+    }
+}
+
+// the client has no Debug
+impl fmt::Debug for Gcs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gcs")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+// the config holds credentials, keep it out of the instrumented spans
+impl fmt::Debug for RunContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunContext")
+            .field("run_id", &self.run_id)
+            .field("execution_date", &self.execution_date)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RunContext {
+    /// Directory the files of `source` for this execution date are staged in, kept apart
+    /// per run so two runs never overwrite each other, per date for backfills and per
+    /// source for instances of the same type.
+    pub fn staging_dir(&self, source: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            run_staging_dir(&self.config, &self.run_id),
+            self.execution_date,
+            source
+        )
+    }
+
+    /// Local path of an output file of `source`, in its staging directory.
+    pub fn output_path(&self, source: &str, file_name: &str) -> std::io::Result<String> {
+        std::fs::create_dir_all(self.staging_dir(source))?;
+        Ok(self.local_path(source, file_name))
+    }
+
+    /// Local path of an output file of `source`, without creating its directory.
+    pub fn local_path(&self, source: &str, file_name: &str) -> String {
+        format!("{}/{}", self.staging_dir(source), file_name)
+    }
+
+    /// Time limit of one unit of work of `source`.
+    pub fn unit_timeout(&self, source: &str, key: &str) -> Duration {
+        self.config.timeouts.for_unit(source, key)
+    }
+
+    /// Format the result file of `table` is written in.
+    pub fn output_format(&self, source: &str, table: &str) -> OutputFormat {
+        self.config.output.for_table(source, table)
+    }
+
+    /// Name a result file is uploaded under once compressed.
+    pub fn upload_file_name(&self, result_file: &str, format: OutputFormat) -> String {
+        self.config.output.compression.file_name(result_file, format)
+    }
 
-        self.client = Some(client);
-        self.bucket_name = Some(bucket_name);
+    /// Local paths a result file goes through, before and after compression.
+    pub fn result_paths(&self, source: &str, result_file: &str, format: OutputFormat) -> Vec<String> {
+        let mut paths = vec![self.local_path(source, result_file)];
+        let file_name = self.upload_file_name(result_file, format);
+        if file_name != result_file {
+            paths.push(self.local_path(source, &file_name));
+        }
+        paths
+    }
+
+    /// Compresses a written result file as configured and describes the object it is
+    /// uploaded as.
+    pub async fn output_object(
+        &self,
+        source: &str,
+        table: &str,
+        result_file: &str,
+        format: OutputFormat,
+    ) -> Result<UploadObject, JobError> {
+        let compression = self.config.output.compression;
+        let file_name = self.upload_file_name(result_file, format);
+        compression.compress(
+            &self.local_path(source, result_file),
+            &self.local_path(source, &file_name),
+            format,
+        )?;
+
+        UploadObject::new(
+            &self.local_path(source, &file_name),
+            &self.output_key(source, table, &file_name),
+            format.content_type(),
+            compression.content_encoding(format),
+        )
+        .await
+    }
+
+    /// Uploads a result file of `table` to every destination of the table.
+    pub async fn upload(
+        &self,
+        source: &str,
+        table: &str,
+        object: &UploadObject,
+    ) -> Result<Vec<DestinationReport>, JobError> {
+        let settings = &self.config.storage;
+        let destinations = self.storage.get(settings.for_table(source, table))?;
+        StoragePlatform::upload_all(destinations, object, settings.policy).await
+    }
+
+    /// Id of a unit of work in the checkpoints, unique across the dates of a backfill.
+    pub fn unit_id(&self, source: &str, key: &str) -> String {
+        format!("{}/{}/{}", self.execution_date, source, key)
+    }
 
+    /// Spawns one unit of work of `source` on the scheduler. A unit an earlier attempt of
+    /// the run already completed is skipped, a unit that succeeds is checkpointed and its
+    /// `partial_files` removed unless the artifacts are kept. A unit that times out or is
+    /// cancelled loses its `partial_files` too, unless a cursor of it is checkpointed.
+    pub fn spawn_unit<F>(
+        &self,
+        source: &str,
+        key: &str,
+        partial_files: Vec<String>,
+        unit: F,
+    ) -> UnitHandle
+    where
+        F: Future<Output = Result<UnitStats, JobError>> + Send + 'static,
+    {
+        let unit_id = self.unit_id(source, key);
+
+        if let Some(mut stats) = self.checkpoints.completed(&unit_id) {
+            info!("{} already completed in run {}, skipping it", unit_id, self.run_id);
+            stats.resumed = true;
+            return tokio::spawn(async move { (Ok(stats), Duration::ZERO) });
+        }
+
+        let checkpoints = self.checkpoints.clone();
+        let artifacts = (!self.keep_artifacts).then(|| partial_files.clone());
+        let on_abort = {
+            let checkpoints = checkpoints.clone();
+            let unit_id = unit_id.clone();
+            move || {
+                // a checkpointed cursor points into the raw output, run --resume continues it
+                if checkpoints.cursor(&unit_id).is_some() {
+                    warn!("keeping the partial files of {} for run --resume", unit_id);
+                } else {
+                    warn!("removing the partial files of {}", unit_id);
+                    remove_artifacts(&partial_files);
+                }
+            }
+        };
+        self.scheduler.spawn(
+            source,
+            self.unit_timeout(source, key),
+            on_abort,
+            async move {
+                let stats = unit.await?;
+                if let Err(err) = checkpoints.complete(&unit_id, &stats) {
+                    warn!("could not checkpoint {}: {}", unit_id, err);
+                }
+                if let Some(artifacts) = artifacts {
+                    remove_artifacts(&artifacts);
+                }
+                Ok(stats)
+            },
+        )
+    }
+
+    /// Object key an output file of `table` is uploaded under, laid out by the key
+    /// template of `source`.
+    pub fn output_key(&self, source: &str, table: &str, file_name: &str) -> String {
+        self.render_key(self.config.keys.for_source(source), source, table, file_name)
+    }
+
+    /// Object key of the manifest files of `source`.
+    pub fn manifest_key(&self, source: &str, file_name: &str) -> String {
+        self.render_key(&self.config.keys.manifest, source, "", file_name)
+    }
+
+    fn render_key(&self, template: &str, source: &str, table: &str, file_name: &str) -> String {
+        let key = [
+            ("{source}", source),
+            ("{table}", table),
+            ("{run_id}", &self.run_id),
+            ("{file}", file_name),
+        ]
+        .iter()
+        .fold(template.to_string(), |key, (placeholder, value)| {
+            key.replace(placeholder, value)
+        });
+        utility::render_date(&key, &self.execution_date)
+    }
+
+    /// Uploads the manifest of the units of `source`, then its `_SUCCESS` marker, to every
+    /// backend the source is stored in.
+    pub async fn publish_manifest(
+        &self,
+        source: &str,
+        units: &[UnitReport],
+    ) -> Result<(), JobError> {
+        let settings = &self.config.storage;
+        let manifest_path = self.output_path(source, "manifest.json")?;
+        let marker_path = self.output_path(source, "_SUCCESS")?;
+        Manifest::new(&self.run_id, source, &self.execution_date, units)
+            .write_json(&manifest_path)?;
+        std::fs::File::create(&marker_path)?;
+
+        // the marker goes last, a loader that sees it can trust the manifest
+        let objects = [
+            (manifest_path, "manifest.json", "application/json"),
+            (marker_path, "_SUCCESS", "application/octet-stream"),
+        ];
+        for (local_path, file_name, content_type) in &objects {
+            let object = UploadObject::new(
+                local_path,
+                &self.manifest_key(source, file_name),
+                content_type,
+                None,
+            )
+            .await?;
+            let destinations = self.storage.get(&settings.for_source(source))?;
+            StoragePlatform::upload_all(destinations, &object, settings.policy).await?;
+        }
+        info!(
+            "published the manifest of {} units of {}",
+            units.len(),
+            source
+        );
+
+        if !self.keep_artifacts {
+            let files: Vec<String> = objects.into_iter().map(|(path, _, _)| path).collect();
+            remove_artifacts(&files);
+        }
         Ok(())
     }
+
+    /// Object key a result file is uploaded under once compressed.
+    pub fn upload_key(
+        &self,
+        source: &str,
+        table: &str,
+        result_file: &str,
+        format: OutputFormat,
+    ) -> String {
+        self.output_key(source, table, &self.upload_file_name(result_file, format))
+    }
 }
 
 impl AtJobDetail {
@@ -100,21 +643,135 @@ impl AtJobDetail {
             airtable_url: String::from(""),
             auth_token: String::from(""),
             offset_value: String::from(""),
+            rate_limit: None,
         }
     }
 }
 
 impl StoragePlatform {
-    pub async fn upload<T: Storage>(
-        mut storage: T,
-        filename: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        storage.init().await?;
-        storage.upload(filename).await?;
-        Ok(())
+    /// Uploads `object` to every destination at the same time. Fails with every
+    /// destination's outcome when the failed ones break `policy`.
+    pub async fn upload_all(
+        destinations: Vec<Arc<StorageBackend>>,
+        object: &UploadObject,
+        policy: UploadPolicy,
+    ) -> Result<Vec<DestinationReport>, JobError> {
+        let reports: Vec<DestinationReport> =
+            futures::future::join_all(destinations.into_iter().map(|storage| async move {
+                let backend = storage.name();
+                let result = storage.upload(object).await;
+                if let Err(err) = &result {
+                    warn!("upload of {} to {} failed: {}", object.key, backend, err);
+                }
+                DestinationReport::new(backend, &result)
+            }))
+            .await;
+
+        let succeeded = |report: &DestinationReport| report.succeeded;
+        let satisfied = match policy {
+            UploadPolicy::All => reports.iter().all(succeeded),
+            UploadPolicy::First => reports.first().is_some_and(succeeded),
+            UploadPolicy::Any => reports.iter().any(succeeded),
+        };
+        if satisfied {
+            Ok(reports)
+        } else {
+            Err(JobError::Upload(reports))
+        }
     }
 }
 
-pub async fn run_task(task: &impl Tasks) -> Result<(), Box<dyn std::error::Error>> {
-    task.run().await
+/// Staging directory of the run `run_id`.
+pub fn run_staging_dir(config: &PipelineConfig, run_id: &str) -> String {
+    format!("{}/{}", config.paths.staging, run_id)
+}
+
+/// Removes the staged files of a unit of work, those already gone included.
+fn remove_artifacts(files: &[String]) {
+    for file in files {
+        match std::fs::remove_file(file) {
+            Ok(()) => debug!("removed {}", file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => warn!("could not remove {}: {}", file, err),
+        }
+    }
+}
+
+/// Removes the staging directory of a run once its units of work are over. The files of
+/// failed units are left in place for `run --resume`, and so are the directories holding them.
+pub fn clean_staging_dir(config: &PipelineConfig, run_id: &str) {
+    let run_dir = run_staging_dir(config, run_id);
+    let Ok(dates) = std::fs::read_dir(&run_dir) else {
+        return;
+    };
+    for date in dates.flatten() {
+        for source in std::fs::read_dir(date.path()).into_iter().flatten().flatten() {
+            // only succeeds when every unit of the source cleaned up after itself
+            let _ = std::fs::remove_dir(source.path());
+        }
+        let _ = std::fs::remove_dir(date.path());
+    }
+    match std::fs::remove_dir(&run_dir) {
+        Ok(()) => info!("removed staging directory {}", run_dir),
+        Err(_) => warn!("kept the files of the failed units in {}", run_dir),
+    }
+}
+
+/// Waits for the spawned units of work of a source, a panicking unit counts as failed.
+pub async fn join_units(
+    context: &RunContext,
+    source: &str,
+    handles: Vec<(String, UnitHandle)>,
+) -> Vec<UnitReport> {
+    let mut reports = Vec::new();
+
+    for (key, handle) in handles {
+        let (result, duration) = match handle.await {
+            Ok(outcome) => outcome,
+            Err(err) => (Err(JobError::Task(err.to_string())), Duration::ZERO),
+        };
+        if let Err(err) = &result {
+            error!("{} {} failed: {}", source, key, err);
+        }
+        reports.push(UnitReport::new(
+            &context.execution_date,
+            source,
+            &key,
+            result,
+            duration,
+        ));
+    }
+
+    reports
+}
+
+/// Runs a source, a source that cannot list its units is reported as one failed unit.
+/// Once every unit of an unfiltered run succeeded the manifest of the source is published,
+/// a manifest that cannot be is reported as a failed unit as well.
+pub async fn run_source(source: &dyn Source, context: &RunContext) -> Vec<UnitReport> {
+    match source.run().await {
+        Ok(mut reports) => {
+            if !source.filtered() && reports.iter().all(UnitReport::is_success) {
+                let started = Instant::now();
+                if let Err(err) = context.publish_manifest(source.name(), &reports).await {
+                    error!("{} manifest failed: {}", source.name(), err);
+                    reports.push(UnitReport::new(
+                        &context.execution_date,
+                        source.name(),
+                        "manifest",
+                        Err(err),
+                        started.elapsed(),
+                    ));
+                }
+            }
+            reports
+        }
+        Err(err) => vec![UnitReport::new(
+            &context.execution_date,
+            source.name(),
+            "*",
+            Err(err),
+            Duration::ZERO,
+        )],
+    }
 }
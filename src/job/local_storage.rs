@@ -0,0 +1,54 @@
+use std::path::Path;
+
+use super::error::JobError;
+use super::job::{LocalFs, Storage, UploadObject};
+use super::utility;
+
+impl Storage for LocalFs {
+    async fn init(&mut self) -> Result<(), JobError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        Ok(())
+    }
+
+    /// Copies the file to `{dir}/{key}` through a temporary file, so a reader of the
+    /// directory never sees a partial or corrupt object.
+    async fn upload(&self, object: &UploadObject) -> Result<(), JobError> {
+        let target = Path::new(&self.dir).join(&object.key);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let temp_path = target.with_file_name(format!(
+            ".{}.tmp",
+            target.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        tokio::fs::copy(&object.local_path, &temp_path)
+            .await
+            .map_err(|err| {
+                JobError::Storage(format!(
+                    "could not copy {} to {}: {}",
+                    object.local_path,
+                    target.display(),
+                    err
+                ))
+            })?;
+
+        let temp_file = temp_path.to_string_lossy().to_string();
+        let sha256 = tokio::task::spawn_blocking(move || utility::sha256_file(&temp_file))
+            .await
+            .map_err(|err| JobError::Task(err.to_string()))??;
+        if sha256 != object.sha256 {
+            tokio::fs::remove_file(&temp_path).await?;
+            return Err(JobError::Storage(format!(
+                "checksum mismatch for {}: expected sha256 {}, got {}",
+                target.display(),
+                object.sha256,
+                sha256
+            )));
+        }
+        tokio::fs::rename(&temp_path, &target).await?;
+
+        Ok(())
+    }
+}
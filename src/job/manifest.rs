@@ -0,0 +1,77 @@
+use chrono::Local;
+use polars::prelude::DataFrame;
+use serde::{Deserialize, Serialize};
+
+use super::error::JobError;
+use super::summary::UnitReport;
+
+/// Every object a source wrote for one execution date. It is uploaded as `manifest.json`
+/// once all units of the source succeeded, followed by an empty `_SUCCESS` marker that
+/// downstream loaders poll for.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub run_id: String,
+    pub source: String,
+    pub execution_date: String,
+    pub created_at: String,
+    pub objects: Vec<ManifestObject>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestObject {
+    pub unit: String,
+    pub key: String,
+    /// Hex encoded SHA-256 of the object as uploaded, compressed or not.
+    pub sha256: String,
+    pub rows: usize,
+    pub bytes: u64,
+    pub schema: Vec<ColumnSchema>,
+}
+
+/// A column of a result file and its type as written.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ColumnSchema {
+    pub name: String,
+    pub data_type: String,
+}
+
+impl ColumnSchema {
+    pub fn of_frame(df: &DataFrame) -> Vec<ColumnSchema> {
+        df.get_columns()
+            .iter()
+            .map(|column| ColumnSchema {
+                name: column.name().to_string(),
+                data_type: column.dtype().to_string(),
+            })
+            .collect()
+    }
+}
+
+impl Manifest {
+    /// Manifest of the units of `source`, all of which must have succeeded.
+    pub fn new(run_id: &str, source: &str, execution_date: &str, units: &[UnitReport]) -> Self {
+        Manifest {
+            run_id: run_id.to_string(),
+            source: source.to_string(),
+            execution_date: execution_date.to_string(),
+            created_at: Local::now().to_rfc3339(),
+            objects: units
+                .iter()
+                .map(|unit| ManifestObject {
+                    unit: unit.key.clone(),
+                    key: unit.s3_key.clone().unwrap_or_default(),
+                    sha256: unit.sha256.clone().unwrap_or_default(),
+                    rows: unit.rows.unwrap_or_default(),
+                    bytes: unit.bytes.unwrap_or_default(),
+                    schema: unit.schema.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn write_json(&self, file_name: &str) -> Result<(), JobError> {
+        let file = std::fs::File::create(file_name)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
@@ -1,8 +1,23 @@
 pub mod airtable;
+pub mod checkpoint;
 pub mod utility;
 pub mod config;
 pub mod impact;
 pub mod emarsys_bq;
+pub mod error;
+pub mod gcs_storage;
+pub mod http;
+#[allow(clippy::module_inception)]
 pub mod job;
+pub mod local_storage;
+pub mod manifest;
+pub mod output;
+pub mod pagination;
+pub mod plan;
+pub mod registry;
+pub mod rest;
+pub mod rate_limit;
 pub mod s3_storage;
+pub mod scheduler;
+pub mod summary;
 pub mod api_extraction;
\ No newline at end of file
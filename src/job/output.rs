@@ -0,0 +1,392 @@
+use polars::io::avro::AvroWriter;
+use polars::io::{json, parquet};
+use polars::prelude::*;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use super::error::JobError;
+
+/// File format of the result files, picked per source and table in the `output` config.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// Keeps the dtypes of the polars pipelines.
+    Parquet,
+    /// One json object per line.
+    Ndjson,
+    Avro,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Avro => "avro",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Parquet => "application/vnd.apache.parquet",
+            OutputFormat::Ndjson => "application/x-ndjson",
+            OutputFormat::Avro => "application/avro",
+        }
+    }
+
+    /// Writes `df` to `file_name`, replacing the file if it exists. Parquet applies
+    /// `compression` to its pages, the other formats are compressed once written.
+    pub fn write(
+        &self,
+        df: &mut DataFrame,
+        file_name: &str,
+        compression: Compression,
+    ) -> Result<(), JobError> {
+        if *self == OutputFormat::Avro {
+            // avro has no type for the all-null columns of the missing airtable fields
+            let null_columns: Vec<Series> = df
+                .get_columns()
+                .iter()
+                .filter(|column| column.dtype() == &DataType::Null)
+                .map(|column| column.cast(&DataType::String))
+                .collect::<PolarsResult<_>>()?;
+            for column in null_columns {
+                df.with_column(column)?;
+            }
+            // polars 0.37 cannot read back the avro it writes from several chunks
+            df.as_single_chunk();
+        }
+
+        if *self == OutputFormat::Parquet {
+            copy_null_strings(df)?;
+        }
+
+        let mut file = File::create(file_name)?;
+
+        let written = match self {
+            OutputFormat::Csv => CsvWriter::new(&mut file).finish(df),
+            OutputFormat::Parquet => ParquetWriter::new(&mut file)
+                .with_compression(compression.parquet())
+                .finish(df)
+                .map(|_| ()),
+            OutputFormat::Ndjson => JsonWriter::new(&mut file)
+                .with_json_format(JsonFormat::JsonLines)
+                .finish(df),
+            OutputFormat::Avro => AvroWriter::new(&mut file).finish(df),
+        };
+
+        written.map_err(|e| write_error(file_name, e))
+    }
+
+    /// Writer of `file_name` taking the rows a batch at a time. Parquet and ndjson write
+    /// every batch as it comes, polars has no batched csv or avro writer so those formats
+    /// keep their batches in memory until `finish`.
+    pub fn batched(
+        &self,
+        file_name: &str,
+        schema: &Schema,
+        compression: Compression,
+    ) -> Result<BatchWriter, JobError> {
+        let file = File::create(file_name)?;
+        let sink = match self {
+            OutputFormat::Parquet => ParquetWriter::new(file)
+                .with_compression(compression.parquet())
+                .batched(schema)
+                .map(|writer| BatchSink::Parquet(Box::new(writer))),
+            OutputFormat::Ndjson => Ok(BatchSink::Ndjson(json::BatchedWriter::new(file))),
+            OutputFormat::Csv | OutputFormat::Avro => {
+                Ok(BatchSink::Buffered(DataFrame::from(schema)))
+            }
+        };
+        Ok(BatchWriter {
+            format: *self,
+            file_name: file_name.to_string(),
+            compression,
+            sink: sink.map_err(|e| write_error(file_name, e))?,
+        })
+    }
+}
+
+/// Result file written a batch of rows at a time, see `OutputFormat::batched`.
+pub struct BatchWriter {
+    format: OutputFormat,
+    file_name: String,
+    compression: Compression,
+    sink: BatchSink,
+}
+
+enum BatchSink {
+    Parquet(Box<parquet::BatchedWriter<File>>),
+    Ndjson(json::BatchedWriter<File>),
+    Buffered(DataFrame),
+}
+
+impl BatchWriter {
+    pub fn write_batch(&mut self, mut batch: DataFrame) -> Result<(), JobError> {
+        let written = match &mut self.sink {
+            BatchSink::Parquet(writer) => {
+                copy_null_strings(&mut batch).and_then(|_| writer.write_batch(&batch))
+            }
+            BatchSink::Ndjson(writer) => writer.write_batch(&batch),
+            BatchSink::Buffered(frame) => frame.vstack_mut(&batch).map(|_| ()),
+        };
+        written.map_err(|e| write_error(&self.file_name, e))
+    }
+
+    pub fn finish(self) -> Result<(), JobError> {
+        match self.sink {
+            BatchSink::Parquet(mut writer) => writer
+                .finish()
+                .map(|_| ())
+                .map_err(|e| write_error(&self.file_name, e)),
+            BatchSink::Ndjson(_) => Ok(()),
+            BatchSink::Buffered(mut frame) => {
+                self.format.write(&mut frame, &self.file_name, self.compression)
+            }
+        }
+    }
+}
+
+fn write_error(file_name: &str, err: PolarsError) -> JobError {
+    JobError::Storage(format!("error writing {}: {}", file_name, err))
+}
+
+/// The nulls of `list().get` keep the bytes of the values they replace, which the parquet
+/// encoder of polars 0.37 does not expect: such columns are copied out before writing.
+fn copy_null_strings(df: &mut DataFrame) -> PolarsResult<()> {
+    let null_strings: Vec<Series> = df
+        .get_columns()
+        .iter()
+        .filter(|column| column.dtype() == &DataType::String && column.null_count() > 0)
+        .map(|column| {
+            let values: StringChunked = column.str()?.into_iter().collect();
+            Ok(values.with_name(column.name()).into_series())
+        })
+        .collect::<PolarsResult<_>>()?;
+    for column in null_strings {
+        df.with_column(column)?;
+    }
+    Ok(())
+}
+
+/// Compression of the result files, set with `output.compression`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+    /// Snappy framing format.
+    Snappy,
+}
+
+impl Compression {
+    /// Whether a file of `format` is compressed as a whole after it was written.
+    fn compresses_file(&self, format: OutputFormat) -> bool {
+        *self != Compression::None && format != OutputFormat::Parquet
+    }
+
+    /// Name of the file once compressed, e.g. `contacts.csv.gz`.
+    pub fn file_name(&self, file_name: &str, format: OutputFormat) -> String {
+        if !self.compresses_file(format) {
+            return file_name.to_string();
+        }
+        let extension = match self {
+            Compression::None => unreachable!(),
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+            Compression::Snappy => "sz",
+        };
+        format!("{}.{}", file_name, extension)
+    }
+
+    /// `Content-Encoding` of the compressed file.
+    pub fn content_encoding(&self, format: OutputFormat) -> Option<&'static str> {
+        if !self.compresses_file(format) {
+            return None;
+        }
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+            Compression::Snappy => Some("x-snappy-framed"),
+        }
+    }
+
+    /// Compresses `source` into `target` and removes `source`, nothing to do when the
+    /// format is not compressed as a file.
+    pub fn compress(&self, source: &str, target: &str, format: OutputFormat) -> Result<(), JobError> {
+        if !self.compresses_file(format) {
+            return Ok(());
+        }
+
+        let mut reader = BufReader::new(File::open(source)?);
+        let mut encoder = self.writer(BufWriter::new(File::create(target)?))?;
+        io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?.flush()?;
+
+        std::fs::remove_file(source)?;
+        Ok(())
+    }
+
+    /// Wraps `inner` so that everything written to it is compressed.
+    pub fn writer<W: Write>(&self, inner: W) -> io::Result<CompressedWriter<W>> {
+        Ok(match self {
+            Compression::None => CompressedWriter::None(inner),
+            Compression::Gzip => CompressedWriter::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => CompressedWriter::Zstd(zstd::Encoder::new(inner, 0)?),
+            Compression::Snappy => {
+                CompressedWriter::Snappy(Box::new(snap::write::FrameEncoder::new(inner)))
+            }
+        })
+    }
+
+    fn parquet(&self) -> ParquetCompression {
+        match self {
+            Compression::None => ParquetCompression::Uncompressed,
+            Compression::Gzip => ParquetCompression::Gzip(None),
+            Compression::Zstd => ParquetCompression::Zstd(None),
+            Compression::Snappy => ParquetCompression::Snappy,
+        }
+    }
+}
+
+/// Writer compressing into `W`, `finish` writes the end of the compressed stream.
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Snappy(Box<snap::write::FrameEncoder<W>>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressedWriter::None(inner) => Ok(inner),
+            CompressedWriter::Gzip(encoder) => encoder.finish(),
+            CompressedWriter::Zstd(encoder) => encoder.finish(),
+            CompressedWriter::Snappy(encoder) => {
+                encoder.into_inner().map_err(|err| err.into_error())
+            }
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            CompressedWriter::None(inner) => inner,
+            CompressedWriter::Gzip(encoder) => encoder,
+            CompressedWriter::Zstd(encoder) => encoder,
+            CompressedWriter::Snappy(encoder) => encoder.as_mut(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+/// In-memory sink whose clones share their bytes, so a writer owning one clone can be
+/// drained through another, e.g. into the parts of a streamed upload.
+#[derive(Clone, Debug, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Removes and returns the bytes written so far.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::lazy::dsl::{col, lit};
+    use std::io::Cursor;
+
+    fn temp_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn batches_make_up_the_whole_file() {
+        let schema: Schema = [Field::new("id", DataType::String)].into_iter().collect();
+        for format in [OutputFormat::Parquet, OutputFormat::Ndjson, OutputFormat::Avro] {
+            let path = temp_file(&format!("batches.{}", format.extension()));
+            let mut writer = format.batched(&path, &schema, Compression::None).unwrap();
+            writer.write_batch(df!("id" => [Some("a"), None]).unwrap()).unwrap();
+            writer.write_batch(df!("id" => [Some("c")]).unwrap()).unwrap();
+            writer.finish().unwrap();
+
+            let file = File::open(&path).unwrap();
+            let written = match format {
+                OutputFormat::Parquet => ParquetReader::new(file).finish(),
+                OutputFormat::Ndjson => JsonLineReader::new(file).finish(),
+                _ => polars::io::avro::AvroReader::new(file).finish(),
+            }
+            .unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let ids: Vec<Option<&str>> =
+                written.column("id").unwrap().str().unwrap().into_iter().collect();
+            assert_eq!(ids, vec![Some("a"), None, Some("c")], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn parquet_keeps_the_nulls_of_list_items() {
+        // the shape of an airtable list field missing from some records
+        let records = "{\"winner\": [\"w\"]}\n{\"winner\": null}\n";
+        let mut df = JsonLineReader::new(Cursor::new(records))
+            .finish()
+            .unwrap()
+            .lazy()
+            .select([col("winner").list().get(lit(0))])
+            .collect()
+            .unwrap();
+        let path = temp_file("list_items.parquet");
+
+        OutputFormat::Parquet.write(&mut df, &path, Compression::None).unwrap();
+        let written = ParquetReader::new(File::open(&path).unwrap()).finish().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let winners: Vec<Option<&str>> =
+            written.column("winner").unwrap().str().unwrap().into_iter().collect();
+        assert_eq!(winners, vec![Some("w"), None]);
+    }
+}
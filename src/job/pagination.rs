@@ -0,0 +1,426 @@
+use futures::stream::{self, Stream};
+use reqwest::header::{HeaderMap, LINK};
+use reqwest::Url;
+use serde_json::Value;
+use tracing::debug;
+
+use super::config::Pagination;
+use super::error::JobError;
+use super::http::{HttpClient, JsonResponse};
+use super::job::RestApi;
+use super::utility;
+
+/// One page of an api response.
+#[derive(Debug)]
+pub struct Page {
+    /// Position of the page, the first one is 1.
+    pub number: u64,
+    pub body: Value,
+}
+
+/// Every page of `api` from its first one, each requested once the previous one is
+/// consumed. The stream ends after the first error.
+pub fn pages<'a, A: RestApi + ?Sized>(
+    api: &'a A,
+    http: &'a HttpClient,
+) -> impl Stream<Item = Result<Page, JobError>> + 'a {
+    stream::try_unfold(Some((api.first_page(), 0)), move |state| async move {
+        let Some((url, fetched)) = state else {
+            return Ok(None);
+        };
+        let url = url?;
+        let (bucket, rate_limit) = api.rate_limit();
+        let response = http
+            .send_json(api.request(http, &url), &bucket, rate_limit)
+            .await?;
+        let records = api.records(&response.body)?.len();
+        let number = fetched + 1;
+        debug!("page {} {}: {} records", number, url, records);
+
+        let next = api
+            .pagination()
+            .next(&url, &response, records, number)?
+            .map(|next| (Ok(next), number));
+        let page = Page {
+            number,
+            body: response.body,
+        };
+        Ok(Some((page, next)))
+    })
+}
+
+impl Pagination {
+    /// Sets the query parameters of the first page on `url`.
+    pub fn first(&self, url: &mut Url) {
+        match self {
+            Pagination::Page { param, start, .. } => set_param(url, param, &start.to_string()),
+            Pagination::Offset {
+                offset_param,
+                limit_param,
+                limit,
+            } => {
+                set_param(url, offset_param, "0");
+                set_param(url, limit_param, &limit.to_string());
+            }
+            Pagination::None
+            | Pagination::Cursor { .. }
+            | Pagination::NextUrl { .. }
+            | Pagination::Link => (),
+        }
+    }
+
+    /// Url of the page after `url`, none once it was the last one. `pages` counts the pages
+    /// fetched so far.
+    pub fn next(
+        &self,
+        url: &Url,
+        response: &JsonResponse,
+        records: usize,
+        pages: u64,
+    ) -> Result<Option<Url>, JobError> {
+        let body = &response.body;
+        let next = match self {
+            Pagination::None => None,
+            Pagination::Cursor { cursor, param } => token(body, cursor).map(|token| {
+                let mut next = url.clone();
+                set_param(&mut next, param, &token);
+                next
+            }),
+            Pagination::NextUrl { next } => match token(body, next) {
+                Some(next) => Some(join(url, &next)?),
+                None => None,
+            },
+            Pagination::Link => match next_link(&response.headers) {
+                Some(next) => Some(join(url, &next)?),
+                None => None,
+            },
+            Pagination::Page {
+                param,
+                start,
+                total_pages,
+            } => {
+                let last = match total_pages {
+                    Some(path) => {
+                        let total = token(body, path).and_then(|total| total.parse::<u64>().ok());
+                        total.ok_or_else(|| {
+                            JobError::Parse(format!("no page count at {} in {}", path, body))
+                        })? <= pages
+                    }
+                    None => false,
+                };
+                (!last && records > 0).then(|| {
+                    let mut next = url.clone();
+                    set_param(&mut next, param, &(start + pages).to_string());
+                    next
+                })
+            }
+            Pagination::Offset {
+                offset_param,
+                limit,
+                ..
+            } => (records as u64 >= *limit).then(|| {
+                let mut next = url.clone();
+                set_param(&mut next, offset_param, &(pages * limit).to_string());
+                next
+            }),
+        };
+        Ok(next)
+    }
+}
+
+/// Non empty string or number at `path` of a page.
+pub fn token(body: &Value, path: &str) -> Option<String> {
+    match utility::json_path(body, path)? {
+        Value::String(token) if !token.is_empty() => Some(token.clone()),
+        Value::Number(token) => Some(token.to_string()),
+        _ => None,
+    }
+}
+
+/// Sets the `name` query parameter of `url`, replacing any value it had.
+pub fn set_param(url: &mut Url, name: &str, value: &str) {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != name)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(name, value);
+}
+
+fn join(url: &Url, next: &str) -> Result<Url, JobError> {
+    url.join(next)
+        .map_err(|err| JobError::Parse(format!("invalid next page url {}: {}", next, err)))
+}
+
+/// Target of the `rel="next"` link of a `Link` header, e.g.
+/// `<https://api.example.com/items?page=2>; rel="next", <...>; rel="last"`.
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(links)
+        .find_map(|(target, params)| {
+            params
+                .split(';')
+                .filter_map(|param| param.split_once('='))
+                .filter(|(name, _)| name.trim().eq_ignore_ascii_case("rel"))
+                .any(|(_, rels)| {
+                    rels.trim()
+                        .trim_matches('"')
+                        .split_whitespace()
+                        .any(|rel| rel == "next")
+                })
+                .then(|| target.to_string())
+        })
+}
+
+/// Target and parameters of every link of a `Link` header value. Targets are read up to
+/// their `>` and parameters up to the next unquoted `,`, either may hold commas.
+fn links(value: &str) -> Vec<(&str, &str)> {
+    let mut links = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>').map(|end| start + end) else {
+            break;
+        };
+        let target = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let mut quoted = false;
+        let params_end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c == ',' && !quoted
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        links.push((target, &rest[..params_end]));
+        rest = &rest[params_end..];
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+
+    fn response(body: Value) -> JsonResponse {
+        JsonResponse {
+            body,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    fn link_response(links: &[&str]) -> JsonResponse {
+        let mut headers = HeaderMap::new();
+        for link in links {
+            headers.append(LINK, HeaderValue::from_str(link).unwrap());
+        }
+        JsonResponse {
+            body: json!([]),
+            headers,
+        }
+    }
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    fn next(
+        pagination: &Pagination,
+        from: &str,
+        response: &JsonResponse,
+        records: usize,
+        pages: u64,
+    ) -> Option<String> {
+        pagination
+            .next(&url(from), response, records, pages)
+            .unwrap()
+            .map(String::from)
+    }
+
+    #[test]
+    fn page_stops_at_the_page_count() {
+        let pagination = Pagination::Page {
+            param: "page".to_string(),
+            start: 1,
+            total_pages: Some("meta.pages".to_string()),
+        };
+        let body = response(json!({"meta": {"pages": 2}}));
+
+        assert_eq!(
+            next(&pagination, "https://api.test/items?page=1", &body, 10, 1).as_deref(),
+            Some("https://api.test/items?page=2")
+        );
+        assert_eq!(next(&pagination, "https://api.test/items?page=2", &body, 10, 2), None);
+    }
+
+    #[test]
+    fn page_without_a_count_stops_at_an_empty_page() {
+        let pagination = Pagination::Page {
+            param: "page".to_string(),
+            start: 0,
+            total_pages: None,
+        };
+        let body = response(json!([]));
+
+        assert_eq!(
+            next(&pagination, "https://api.test/items?page=0", &body, 3, 1).as_deref(),
+            Some("https://api.test/items?page=1")
+        );
+        assert_eq!(next(&pagination, "https://api.test/items?page=1", &body, 0, 2), None);
+    }
+
+    #[test]
+    fn page_without_its_count_fails() {
+        let pagination = Pagination::Page {
+            param: "page".to_string(),
+            start: 1,
+            total_pages: Some("pages".to_string()),
+        };
+        let result = pagination.next(&url("https://api.test/items"), &response(json!({})), 1, 1);
+
+        assert!(matches!(result, Err(JobError::Parse(_))));
+    }
+
+    #[test]
+    fn offset_stops_at_a_short_page() {
+        let pagination = Pagination::Offset {
+            offset_param: "offset".to_string(),
+            limit_param: "limit".to_string(),
+            limit: 50,
+        };
+        let mut first = url("https://api.test/items?status=open");
+        pagination.first(&mut first);
+        assert_eq!(first.as_str(), "https://api.test/items?status=open&offset=0&limit=50");
+
+        let body = response(json!([]));
+        assert_eq!(
+            next(&pagination, first.as_str(), &body, 50, 1).as_deref(),
+            Some("https://api.test/items?status=open&limit=50&offset=50")
+        );
+        assert_eq!(next(&pagination, first.as_str(), &body, 49, 2), None);
+    }
+
+    #[test]
+    fn cursor_is_set_until_there_is_none() {
+        let pagination = Pagination::Cursor {
+            cursor: "paging.cursor".to_string(),
+            param: "after".to_string(),
+        };
+
+        assert_eq!(
+            next(
+                &pagination,
+                "https://api.test/items?after=a",
+                &response(json!({"paging": {"cursor": "b"}})),
+                1,
+                1
+            )
+            .as_deref(),
+            Some("https://api.test/items?after=b")
+        );
+        assert_eq!(
+            next(&pagination, "https://api.test/items", &response(json!({"paging": {"cursor": ""}})), 1, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn next_url_is_resolved_against_the_page() {
+        let pagination = Pagination::NextUrl {
+            next: "next".to_string(),
+        };
+
+        assert_eq!(
+            next(
+                &pagination,
+                "https://api.test/v1/items?page=1",
+                &response(json!({"next": "/v1/items?page=2"})),
+                1,
+                1
+            )
+            .as_deref(),
+            Some("https://api.test/v1/items?page=2")
+        );
+        assert_eq!(
+            next(
+                &pagination,
+                "https://api.test/v1/items?page=1",
+                &response(json!({"next": "items?page=2"})),
+                1,
+                1
+            )
+            .as_deref(),
+            Some("https://api.test/v1/items?page=2")
+        );
+        assert_eq!(
+            next(&pagination, "https://api.test/v1/items", &response(json!({"next": null})), 1, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn link_follows_the_next_rel() {
+        let headers = link_response(&[
+            r#"<https://api.test/items?page=1>; rel="first", <https://api.test/items?page=3>; rel="next", <https://api.test/items?page=9>; rel="last""#,
+        ]);
+
+        assert_eq!(
+            next(&Pagination::Link, "https://api.test/items?page=2", &headers, 1, 2).as_deref(),
+            Some("https://api.test/items?page=3")
+        );
+    }
+
+    #[test]
+    fn link_reads_every_header_and_rel_list() {
+        let headers = link_response(&[
+            r#"<https://api.test/items?page=1>; rel="prev first""#,
+            r#"</items?page=3>; title="a, b"; rel="last next""#,
+        ]);
+
+        assert_eq!(
+            next(&Pagination::Link, "https://api.test/items?page=2", &headers, 1, 2).as_deref(),
+            Some("https://api.test/items?page=3")
+        );
+    }
+
+    #[test]
+    fn link_targets_may_hold_commas() {
+        let headers = link_response(&[
+            r#"<https://api.test/items?fields=id,name&page=2>; rel=next, <https://api.test/items?fields=id,name&page=5>; rel=last"#,
+        ]);
+
+        assert_eq!(
+            next(&Pagination::Link, "https://api.test/items?fields=id,name", &headers, 1, 1).as_deref(),
+            Some("https://api.test/items?fields=id,name&page=2")
+        );
+    }
+
+    #[test]
+    fn link_without_next_is_the_last_page() {
+        let headers = link_response(&[r#"<https://api.test/items?page=1>; rel="first""#]);
+
+        assert_eq!(next(&Pagination::Link, "https://api.test/items", &headers, 1, 1), None);
+        assert_eq!(next(&Pagination::Link, "https://api.test/items", &response(json!([])), 1, 1), None);
+    }
+
+    #[test]
+    fn token_reads_strings_and_numbers() {
+        let body = json!({"a": {"text": "x", "number": 42, "empty": "", "list": [1]}});
+
+        assert_eq!(token(&body, "a.text").as_deref(), Some("x"));
+        assert_eq!(token(&body, "a.number").as_deref(), Some("42"));
+        assert_eq!(token(&body, "a.empty"), None);
+        assert_eq!(token(&body, "a.list"), None);
+        assert_eq!(token(&body, "a.missing"), None);
+    }
+}
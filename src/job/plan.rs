@@ -0,0 +1,37 @@
+use serde::Serialize;
+
+use super::utility;
+
+/// One unit of work a run would execute, resolved without calling any API.
+#[derive(Clone, Debug, Serialize)]
+pub struct PlanEntry {
+    pub execution_date: String,
+    pub source: String,
+    pub key: String,
+    pub request: String,
+    pub s3_key: String,
+}
+
+pub fn print_json(entries: &[PlanEntry]) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(entries)?);
+    Ok(())
+}
+
+pub fn print_table(entries: &[PlanEntry]) {
+    // rendered sql spans several lines, keep every unit on a single row
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|entry| {
+            vec![
+                entry.execution_date.clone(),
+                entry.source.clone(),
+                entry.key.clone(),
+                entry.s3_key.clone(),
+                entry.request.split_whitespace().collect::<Vec<_>>().join(" "),
+            ]
+        })
+        .collect();
+
+    utility::print_table(&["EXECUTION_DATE", "SOURCE", "KEY", "S3_KEY", "REQUEST"], &rows);
+    println!("{} unit(s) of work", rows.len());
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use super::config::RateLimit;
+
+/// Token buckets keyed by whatever the api rate limits on, e.g. an airtable base url.
+/// Clones share their buckets.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Waits until one more request against `key` fits in `limit`.
+    pub async fn acquire(&self, key: &str, limit: &RateLimit) {
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+                let burst = limit.burst() as f64;
+                let now = Instant::now();
+                let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+                    tokens: burst,
+                    refilled_at: now,
+                });
+
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * limit.requests_per_second).min(burst);
+                bucket.refilled_at = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / limit.requests_per_second)
+            };
+            sleep(wait).await;
+        }
+    }
+}
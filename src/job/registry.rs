@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use super::config::{PipelineConfig, SourceConfig};
+use super::error::JobError;
+use super::job::{Airtable, EmarsysBq, Impact, Rest, RunContext, Source};
+
+/// Filters narrowing down the units of work of a source, e.g. `endpoint=order_sheet`.
+pub type UnitFilters = HashMap<String, String>;
+
+/// Builds an instance of a source type from its name, its settings and the filters of the run.
+pub type SourceBuilder =
+    fn(&str, &SourceConfig, RunContext, &UnitFilters) -> Result<Box<dyn Source>, JobError>;
+
+/// A kind of source the `type` of a source instance can refer to.
+#[derive(Clone, Copy)]
+pub struct SourceType {
+    /// Filters the units of work of the type can be narrowed down by.
+    pub filters: &'static [&'static str],
+    pub build: SourceBuilder,
+}
+
+/// Every source type by name. New types are registered here rather than matched on in main.
+pub struct SourceRegistry {
+    types: HashMap<String, SourceType>,
+}
+
+impl Default for SourceRegistry {
+    fn default() -> Self {
+        let mut registry = SourceRegistry {
+            types: HashMap::new(),
+        };
+        registry.register("airtable", Airtable::FILTERS, Airtable::build);
+        registry.register("emarsys", EmarsysBq::FILTERS, EmarsysBq::build);
+        registry.register("impact", Impact::FILTERS, Impact::build);
+        registry.register("rest", Rest::FILTERS, Rest::build);
+        registry
+    }
+}
+
+impl SourceRegistry {
+    pub fn register(&mut self, kind: &str, filters: &'static [&'static str], build: SourceBuilder) {
+        self.types
+            .insert(kind.to_string(), SourceType { filters, build });
+    }
+
+    fn source_type(&self, name: &str, config: &SourceConfig) -> Result<&SourceType, JobError> {
+        self.types.get(&config.kind).ok_or_else(|| {
+            JobError::Config(format!(
+                "source {} has unknown type {}, expected one of {}",
+                name,
+                config.kind,
+                self.kinds().join(", ")
+            ))
+        })
+    }
+
+    fn kinds(&self) -> Vec<&str> {
+        let mut kinds: Vec<&str> = self.types.keys().map(String::as_str).collect();
+        kinds.sort();
+        kinds
+    }
+
+    /// Builds the source instances `names`, every instance of the config when empty. Every
+    /// filter must be supported by all the selected instances.
+    pub fn build(
+        &self,
+        config: &PipelineConfig,
+        names: &[String],
+        filters: &UnitFilters,
+        context: &RunContext,
+    ) -> Result<Vec<Box<dyn Source>>, JobError> {
+        let instances = config.source_instances()?;
+        let names: Vec<&String> = if names.is_empty() {
+            instances.keys().collect()
+        } else {
+            names.iter().collect()
+        };
+
+        let mut sources = Vec::new();
+        for name in names {
+            let instance = instances.get(name).ok_or_else(|| {
+                JobError::Config(format!(
+                    "unknown source {}, expected one of {}",
+                    name,
+                    instances.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            })?;
+            let source_type = self.source_type(name, instance)?;
+            if let Some(filter) = filters
+                .keys()
+                .find(|filter| !source_type.filters.contains(&filter.as_str()))
+            {
+                return Err(JobError::Config(format!(
+                    "source {} of type {} cannot be filtered by {}, only by {}",
+                    name,
+                    instance.kind,
+                    filter,
+                    source_type.filters.join(", ")
+                )));
+            }
+            sources.push((source_type.build)(name, instance, context.clone(), filters)?);
+        }
+
+        Ok(sources)
+    }
+
+    /// Problems of every source instance of the config.
+    pub async fn validate(&self, config: &PipelineConfig, context: &RunContext) -> Vec<String> {
+        let instances = match config.source_instances() {
+            Ok(instances) => instances,
+            Err(err) => return vec![err.to_string()],
+        };
+
+        let mut problems = Vec::new();
+        for (name, instance) in &instances {
+            let built = self.source_type(name, instance).and_then(|source_type| {
+                (source_type.build)(name, instance, context.clone(), &UnitFilters::new())
+            });
+            match built {
+                Ok(source) => problems.extend(source.validate().await),
+                Err(err) => problems.push(err.to_string()),
+            }
+        }
+        problems
+    }
+}
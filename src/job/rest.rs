@@ -0,0 +1,284 @@
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use polars::prelude::*;
+use polars_sql::SQLContext;
+use reqwest::Url;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::pin::pin;
+use tracing::{info, warn};
+
+use super::config::{check_file, Pagination, RestTable, SourceConfig};
+use super::error::JobError;
+use super::job::{join_units, Rest, RestApi, RunContext, Source, Tasks};
+use super::manifest::ColumnSchema;
+use super::pagination::set_param;
+use super::output::OutputFormat;
+use super::plan::PlanEntry;
+use super::registry::UnitFilters;
+use super::summary::{UnitReport, UnitStats};
+use super::utility::render_date;
+
+impl Rest {
+    /// Filters the units of work can be narrowed down by.
+    pub const FILTERS: &'static [&'static str] = &["table"];
+
+    pub fn build(
+        name: &str,
+        config: &SourceConfig,
+        context: RunContext,
+        filters: &UnitFilters,
+    ) -> Result<Box<dyn Source>, JobError> {
+        Ok(Box::new(Rest {
+            context,
+            name: name.to_string(),
+            settings: config.settings(name)?,
+            table_name: String::new(),
+            table_filter: filters.get("table").cloned(),
+        }))
+    }
+
+    /// Enabled tables, narrowed down by the table filter.
+    pub fn selected_tables(&self) -> Result<Vec<&RestTable>, JobError> {
+        let tables: Vec<&RestTable> = self
+            .settings
+            .tables
+            .iter()
+            .filter(|table| table.enabled)
+            .filter(|table| {
+                self.table_filter
+                    .as_ref()
+                    .is_none_or(|name| name == &table.name)
+            })
+            .collect();
+
+        if tables.is_empty() {
+            return Err(JobError::Config(format!(
+                "no table of {} matches the given table filter",
+                self.name
+            )));
+        }
+        Ok(tables)
+    }
+
+    pub fn table(&self) -> Result<&RestTable, JobError> {
+        self.settings
+            .tables
+            .iter()
+            .find(|table| table.name == self.table_name)
+            .ok_or_else(|| {
+                JobError::Config(format!("{} has no table {}", self.name, self.table_name))
+            })
+    }
+
+    /// Url of the first page of `table` for the execution date.
+    pub fn table_url(&self, table: &RestTable) -> Result<Url, JobError> {
+        let date = &self.context.execution_date;
+        let mut url = Url::parse(&render_date(&table.url, date)).map_err(|err| {
+            JobError::Config(format!("invalid url of {}/{}: {}", self.name, table.name, err))
+        })?;
+        for (name, value) in &table.query {
+            set_param(&mut url, name, &render_date(value, date));
+        }
+        table.pagination.first(&mut url);
+        Ok(url)
+    }
+
+    pub fn output_file(&self) -> String {
+        format!("{}_output.json", self.table_name)
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.context.output_format(self.source(), &self.table_name)
+    }
+
+    pub fn result_file(&self) -> String {
+        format!(
+            "result_{}.{}",
+            self.table_name,
+            self.output_format().extension()
+        )
+    }
+}
+
+impl Tasks for Rest {
+    fn source(&self) -> &str {
+        &self.name
+    }
+
+    #[tracing::instrument(err, skip_all, fields(table = %self.table_name))]
+    async fn extraction(&mut self) -> Result<usize, JobError> {
+        let mut file =
+            File::create(self.context.output_path(self.source(), &self.output_file())?)?;
+        let http = self.context.http.clone();
+        let mut pages = 0;
+        let mut records = 0;
+
+        let mut stream = pin!(self.pages(&http));
+        while let Some(page) = stream.try_next().await? {
+            let data = self.records(&page.body)?;
+            for d in data {
+                serde_json::to_writer(&mut file, &d)?;
+                writeln!(&mut file)?;
+            }
+            pages = page.number;
+            records += data.len();
+        }
+
+        info!("extracted {} records of {} in {} page(s)", records, self.table_name, pages);
+        Ok(records)
+    }
+
+    #[tracing::instrument(err, skip_all)]
+    async fn execute(&mut self) -> Result<UnitStats, JobError> {
+        let records = self.extraction().await?;
+        let table = self.table()?.clone();
+
+        let df = if records == 0 {
+            warn!("{}/{} returned no records", self.name, table.name);
+            DataFrame::default()
+        } else {
+            let mut file =
+                File::open(self.context.output_path(self.source(), &self.output_file())?)?;
+            JsonLineReader::new(&mut file)
+                .finish()?
+                .unnest(&table.unnest)?
+        };
+
+        let mut df = match &table.transform {
+            Some(transform) if records > 0 => {
+                let query = std::fs::read_to_string(transform).map_err(|err| {
+                    JobError::Config(format!("could not read transform {}: {}", transform, err))
+                })?;
+                let mut ctx = SQLContext::new();
+                ctx.register("records", df.lazy());
+                ctx.execute(&query)?.collect()?
+            }
+            _ => df,
+        };
+
+        let format = self.output_format();
+        let result_file = self.result_file();
+        let file_name = self.context.output_path(self.source(), &result_file)?;
+        format.write(&mut df, &file_name, self.context.config.output.compression)?;
+        info!("success write {}", file_name);
+
+        let object = self.context.output_object(
+            self.source(),
+            &self.table_name,
+            &result_file,
+            format,
+        )
+        .await?;
+        let mut stats = UnitStats::for_object(&object, df.height(), ColumnSchema::of_frame(&df))?;
+        stats.destinations = self
+            .context
+            .upload(self.source(), &self.table_name, &object)
+            .await?;
+        Ok(stats)
+    }
+}
+
+impl Rest {
+    #[tracing::instrument(err, skip_all, fields(source = %self.name))]
+    async fn run_units(&self) -> Result<Vec<UnitReport>, JobError> {
+        let handles: Vec<_> = self
+            .selected_tables()?
+            .into_iter()
+            .map(|table| {
+                let mut rest_clone = self.clone();
+                rest_clone.table_name = table.name.clone();
+                let key = table.name.clone();
+                let mut partial_files =
+                    vec![self.context.local_path(self.source(), &rest_clone.output_file())];
+                partial_files.extend(self.context.result_paths(
+                    self.source(),
+                    &rest_clone.result_file(),
+                    rest_clone.output_format(),
+                ));
+                let handle = self.context.spawn_unit(
+                    self.source(),
+                    &key,
+                    partial_files,
+                    async move { rest_clone.execute().await },
+                );
+                (key, handle)
+            })
+            .collect();
+
+        Ok(join_units(&self.context, self.source(), handles).await)
+    }
+
+    #[tracing::instrument(err, skip_all)]
+    async fn plan_units(&self) -> Result<Vec<PlanEntry>, JobError> {
+        self.selected_tables()?
+            .into_iter()
+            .map(|table| {
+                let mut unit = self.clone();
+                unit.table_name = table.name.clone();
+                let format = unit.output_format();
+                Ok(PlanEntry {
+                    execution_date: self.context.execution_date.clone(),
+                    source: self.source().to_string(),
+                    key: table.name.clone(),
+                    request: self.table_url(table)?.to_string(),
+                    s3_key: self.context.upload_key(
+                        self.source(),
+                        &table.name,
+                        &unit.result_file(),
+                        format,
+                    ),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Source for Rest {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self) -> BoxFuture<'_, Result<Vec<UnitReport>, JobError>> {
+        Box::pin(self.run_units())
+    }
+
+    fn plan(&self) -> BoxFuture<'_, Result<Vec<PlanEntry>, JobError>> {
+        Box::pin(self.plan_units())
+    }
+
+    fn validate(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut problems = Vec::new();
+            if self.settings.tables.is_empty() {
+                problems.push(format!("{}.tables is empty", self.name));
+            }
+
+            let mut names = HashSet::new();
+            for table in &self.settings.tables {
+                if !names.insert(&table.name) {
+                    problems.push(format!("{} has two tables named {}", self.name, table.name));
+                }
+                if let Err(err) = self.table_url(table) {
+                    problems.push(err.to_string());
+                }
+                if let Some(transform) = &table.transform {
+                    check_file(transform, &mut problems);
+                }
+                if let Pagination::Offset { limit: 0, .. } = table.pagination {
+                    problems.push(format!(
+                        "{}/{} pagination limit must be at least 1",
+                        self.name, table.name
+                    ));
+                }
+            }
+
+            problems
+        })
+    }
+
+    fn filtered(&self) -> bool {
+        self.table_filter.is_some()
+    }
+}
@@ -1,28 +1,437 @@
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{ChecksumAlgorithm, ChecksumMode, CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
 use std::path::Path;
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
 
-use aws_sdk_s3::primitives::ByteStream;
+use super::config::MultipartConfig;
+use super::error::JobError;
+use super::http::Backoff;
+use super::job::{AwsS3, Storage, UploadObject};
 
-use super::job::{AwsS3, Storage};
+/// S3 caps a multipart upload at this many parts.
+const MAX_PARTS: u64 = 10_000;
+/// Largest part s3 accepts.
+const MAX_PART_SIZE: usize = 5 * 1024 * 1024 * 1024;
+/// Parts of an upload of unknown size between two doublings of its part size. Parts of
+/// 5 MiB doubled this often hold more than 5 TiB, the largest object, within `MAX_PARTS`;
+/// doubling every 1000 parts falls just short of it.
+const PARTS_PER_DOUBLING: i32 = 900;
 
 impl Storage for AwsS3 {
-    async fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.new().await?;
+    async fn init(&mut self) -> Result<(), JobError> {
+        self.connect().await?;
 
         Ok(())
     }
 
-    async fn upload(&self, file_name: String) -> Result<(), Box<dyn std::error::Error>> {
-        let body = ByteStream::from_path(Path::new(&file_name)).await;
+    async fn upload(&self, object: &UploadObject) -> Result<(), JobError> {
+        let size = tokio::fs::metadata(&object.local_path).await?.len();
+        if size > self.settings.multipart.threshold_bytes() {
+            return self.upload_multipart(object, size).await;
+        }
+
+        let body = ByteStream::from_path(Path::new(&object.local_path))
+            .await
+            .map_err(|err| {
+                JobError::Storage(format!("could not read {}: {}", object.local_path, err))
+            })?;
+        let checksum = hex::decode(&object.sha256)
+            .map(|digest| BASE64.encode(digest))
+            .map_err(|err| JobError::Storage(format!("invalid sha256 of {}: {}", object.key, err)))?;
         let client = self.client.as_ref().unwrap();
-        let bucket_name = self.bucket_name.as_ref().unwrap();
+        let bucket_name = &self.settings.bucket_name;
+        // s3 rejects the body when it does not match the checksum header
         client
             .put_object()
             .bucket(bucket_name)
-            .key(file_name)
-            .body(body.unwrap())
+            .key(&object.key)
+            .content_type(&object.content_type)
+            .set_content_encoding(object.content_encoding.clone())
+            .checksum_sha256(&checksum)
+            .body(body)
             .send()
+            .await
+            .map_err(|err| s3_error("could not upload", &object.key, bucket_name, err))?;
+
+        verify_checksum(client, bucket_name, &object.key, &checksum).await
+    }
+}
+
+impl AwsS3 {
+    /// Uploads a local file in parts read one after the other, at most
+    /// `multipart.parallelism` of them in memory at a time.
+    async fn upload_multipart(&self, object: &UploadObject, size: u64) -> Result<(), JobError> {
+        // grow the parts of huge files so they fit in the part limit
+        let part_size = self
+            .settings
+            .multipart
+            .part_size_bytes()
+            .max(size.div_ceil(MAX_PARTS));
+        let mut upload = self
+            .start_multipart(&object.key, &object.content_type, object.content_encoding.as_deref())
             .await?;
 
+        push_file(&mut upload, &object.local_path, part_size).await?;
+
+        let sha256 = upload.complete().await?;
+        if sha256 != object.sha256 {
+            return Err(JobError::Storage(format!(
+                "checksum mismatch for {}: expected sha256 {}, uploaded {}",
+                object.key, object.sha256, sha256
+            )));
+        }
+        info!("uploaded {} in {} bytes parts", object.key, part_size);
+        Ok(())
+    }
+
+    /// Starts a multipart upload to `key`, parts are pushed as they are produced.
+    pub async fn start_multipart(
+        &self,
+        key: &str,
+        content_type: &str,
+        content_encoding: Option<&str>,
+    ) -> Result<MultipartUpload, JobError> {
+        let client = self.client.clone().unwrap();
+        let bucket_name = self.settings.bucket_name.clone();
+        let created = client
+            .create_multipart_upload()
+            .bucket(&bucket_name)
+            .key(key)
+            .content_type(content_type)
+            .set_content_encoding(content_encoding.map(String::from))
+            .checksum_algorithm(ChecksumAlgorithm::Sha256)
+            .send()
+            .await
+            .map_err(|err| s3_error("could not start the upload of", key, &bucket_name, err))?;
+        let upload_id = created.upload_id().map(String::from).ok_or_else(|| {
+            JobError::Storage(format!("s3 returned no upload id for {}", key))
+        })?;
+
+        Ok(MultipartUpload {
+            client,
+            bucket_name,
+            key: key.to_string(),
+            upload_id,
+            settings: self.settings.multipart.clone(),
+            backoff: self.backoff,
+            in_flight: JoinSet::new(),
+            parts: Vec::new(),
+            next_part: 1,
+            bytes: 0,
+            sha256: Sha256::new(),
+            part_digests: Vec::new(),
+            completed: false,
+        })
+    }
+}
+
+/// An S3 multipart upload in progress. Every part is sent with its SHA-256 and the
+/// composite checksum of the object is checked once it is complete. Failing parts are
+/// retried, an upload dropped before it is complete, failed or not, is aborted so S3
+/// does not keep its parts around.
+#[derive(Debug)]
+pub struct MultipartUpload {
+    client: Client,
+    bucket_name: String,
+    key: String,
+    upload_id: String,
+    settings: MultipartConfig,
+    backoff: Backoff,
+    in_flight: JoinSet<Result<CompletedPart, JobError>>,
+    parts: Vec<CompletedPart>,
+    next_part: i32,
+    bytes: u64,
+    /// Digest of every byte pushed, in order.
+    sha256: Sha256,
+    part_digests: Vec<[u8; 32]>,
+    /// Set once S3 completed the upload, there is nothing left to abort on drop.
+    completed: bool,
+}
+
+impl MultipartUpload {
+    /// Bytes pushed so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Size the next part of an upload of unknown size should reach, `part_size` doubled
+    /// every `PARTS_PER_DOUBLING` parts so that the upload fits in the part limit.
+    pub fn next_part_size(&self, part_size: usize) -> usize {
+        part_size_at(self.next_part, part_size)
+    }
+
+    /// Uploads the next part in the background, waiting first when
+    /// `multipart.parallelism` parts are already in flight.
+    pub async fn push_part(&mut self, part: Vec<u8>) -> Result<(), JobError> {
+        if self.next_part as u64 > MAX_PARTS {
+            return Err(JobError::Storage(format!(
+                "{} needs more than the {} parts of a multipart upload",
+                self.key, MAX_PARTS
+            )));
+        }
+        while self.in_flight.len() >= self.settings.parallelism {
+            self.join_next().await?;
+        }
+
+        let part_number = self.next_part;
+        self.next_part += 1;
+        self.bytes += part.len() as u64;
+        self.sha256.update(&part);
+        let digest: [u8; 32] = Sha256::digest(&part).into();
+        self.part_digests.push(digest);
+        let checksum = BASE64.encode(digest);
+
+        let client = self.client.clone();
+        let (bucket_name, key, upload_id) = (
+            self.bucket_name.clone(),
+            self.key.clone(),
+            self.upload_id.clone(),
+        );
+        let max_attempts = self.settings.max_attempts;
+        let backoff = self.backoff;
+        let body = Bytes::from(part);
+
+        self.in_flight.spawn(async move {
+            let mut attempt = 1;
+            loop {
+                let sent = client
+                    .upload_part()
+                    .bucket(&bucket_name)
+                    .key(&key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .checksum_sha256(&checksum)
+                    .body(ByteStream::from(body.clone()))
+                    .send()
+                    .await;
+
+                match sent {
+                    Ok(output) => {
+                        return Ok(CompletedPart::builder()
+                            .part_number(part_number)
+                            .set_e_tag(output.e_tag().map(String::from))
+                            .checksum_sha256(checksum)
+                            .build())
+                    }
+                    Err(err) if attempt < max_attempts => {
+                        let delay = backoff.delay(attempt);
+                        warn!(
+                            "part {} of {} failed: {}, retrying in {:?}",
+                            part_number,
+                            key,
+                            aws_sdk_s3::error::DisplayErrorContext(&err),
+                            delay
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    Err(err) => {
+                        return Err(s3_error(
+                            &format!("could not upload part {} of", part_number),
+                            &key,
+                            &bucket_name,
+                            err,
+                        ))
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Waits for the parts in flight and completes the upload, dropped and so aborted on
+    /// failure. Returns the hex encoded SHA-256 of the whole object.
+    pub async fn complete(mut self) -> Result<String, JobError> {
+        self.finish().await?;
+
+        // s3 checksums a multipart object as the digest of its part digests
+        let mut composite = Sha256::new();
+        self.part_digests.iter().for_each(|digest| composite.update(digest));
+        let checksum = format!(
+            "{}-{}",
+            BASE64.encode(composite.finalize()),
+            self.part_digests.len()
+        );
+        verify_checksum(&self.client, &self.bucket_name, &self.key, &checksum).await?;
+
+        Ok(hex::encode(std::mem::take(&mut self.sha256).finalize()))
+    }
+
+    async fn finish(&mut self) -> Result<(), JobError> {
+        while !self.in_flight.is_empty() {
+            self.join_next().await?;
+        }
+
+        let mut parts = std::mem::take(&mut self.parts);
+        parts.sort_by_key(|part| part.part_number());
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|err| {
+                s3_error("could not complete the upload of", &self.key, &self.bucket_name, err)
+            })?;
+
+        self.completed = true;
         Ok(())
     }
+
+    async fn join_next(&mut self) -> Result<(), JobError> {
+        match self.in_flight.join_next().await {
+            Some(Ok(Ok(part))) => {
+                self.parts.push(part);
+                Ok(())
+            }
+            Some(Ok(Err(err))) => Err(err),
+            Some(Err(err)) => Err(JobError::Task(err.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Size part `part_number` of an upload of unknown size should reach, `part_size` doubled
+/// every `PARTS_PER_DOUBLING` parts.
+fn part_size_at(part_number: i32, part_size: usize) -> usize {
+    let doublings = ((part_number - 1) / PARTS_PER_DOUBLING) as u32;
+    part_size
+        .saturating_mul(2usize.saturating_pow(doublings))
+        .min(MAX_PART_SIZE)
+}
+
+async fn push_file(upload: &mut MultipartUpload, path: &str, part_size: u64) -> Result<(), JobError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    loop {
+        let mut part = Vec::with_capacity(part_size as usize);
+        (&mut file).take(part_size).read_to_end(&mut part).await?;
+        if part.is_empty() {
+            return Ok(());
+        }
+        upload.push_part(part).await?;
+    }
+}
+
+// the only place an upload is aborted: after a failure, or when a unit of work cancelled
+// or timed out mid upload drops it without completing it
+impl Drop for MultipartUpload {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        self.completed = true;
+        self.in_flight.abort_all();
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("could not abort the upload of {}", self.key);
+            return;
+        };
+        runtime.spawn(abort_upload(
+            self.client.clone(),
+            self.bucket_name.clone(),
+            self.key.clone(),
+            self.upload_id.clone(),
+        ));
+    }
+}
+
+/// Checks the SHA-256 s3 stored for `key` against `expected`. A store that reports none,
+/// like some s3 compatible ones, is trusted on the checks it made during the upload.
+async fn verify_checksum(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    expected: &str,
+) -> Result<(), JobError> {
+    let head = client
+        .head_object()
+        .bucket(bucket_name)
+        .key(key)
+        .checksum_mode(ChecksumMode::Enabled)
+        .send()
+        .await
+        .map_err(|err| s3_error("could not check", key, bucket_name, err))?;
+
+    match head.checksum_sha256() {
+        Some(stored) if stored == expected => Ok(()),
+        Some(stored) => Err(JobError::Storage(format!(
+            "checksum mismatch for {} in s3://{}: expected sha256 {}, stored {}",
+            key, bucket_name, expected, stored
+        ))),
+        None => {
+            warn!("s3://{}/{} has no sha256 to check", bucket_name, key);
+            Ok(())
+        }
+    }
+}
+
+async fn abort_upload(client: Client, bucket_name: String, key: String, upload_id: String) {
+    let aborted = client
+        .abort_multipart_upload()
+        .bucket(&bucket_name)
+        .key(&key)
+        .upload_id(&upload_id)
+        .send()
+        .await;
+    match aborted {
+        Ok(_) => info!("aborted the upload of {}", key),
+        Err(err) => warn!(
+            "could not abort the upload of {}: {}",
+            key,
+            aws_sdk_s3::error::DisplayErrorContext(&err)
+        ),
+    }
+}
+
+fn s3_error<E: std::error::Error>(action: &str, key: &str, bucket_name: &str, err: E) -> JobError {
+    JobError::Storage(format!(
+        "{} {} to s3://{}: {}",
+        action,
+        key,
+        bucket_name,
+        aws_sdk_s3::error::DisplayErrorContext(&err)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    #[test]
+    fn parts_start_at_the_configured_size() {
+        assert_eq!(part_size_at(1, 5 * MIB), 5 * MIB);
+        assert_eq!(part_size_at(PARTS_PER_DOUBLING, 5 * MIB), 5 * MIB);
+        assert_eq!(part_size_at(PARTS_PER_DOUBLING + 1, 5 * MIB), 10 * MIB);
+    }
+
+    #[test]
+    fn parts_of_the_smallest_size_hold_the_largest_object_within_the_part_limit() {
+        let total: u64 = (1..=MAX_PARTS as i32)
+            .map(|part| part_size_at(part, 5 * MIB) as u64)
+            .sum();
+        assert!(total >= 5 * 1024 * 1024 * MIB as u64, "{} bytes", total);
+    }
+
+    #[test]
+    fn parts_never_exceed_the_largest_part_size() {
+        assert_eq!(part_size_at(MAX_PARTS as i32, 5 * MIB), MAX_PART_SIZE);
+        assert_eq!(part_size_at(MAX_PARTS as i32, MAX_PART_SIZE), MAX_PART_SIZE);
+        assert_eq!(part_size_at(i32::MAX, usize::MAX), MAX_PART_SIZE);
+    }
 }
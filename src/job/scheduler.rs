@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use super::config::ConcurrencyConfig;
+use super::error::JobError;
+use super::summary::UnitStats;
+
+/// Spawned unit of work, resolves to its result and how long it took.
+pub type UnitHandle = JoinHandle<(Result<UnitStats, JobError>, Duration)>;
+
+/// Runs the units of work of every source under the global and per-source caps of the
+/// `concurrency` config. One scheduler is shared by the whole run, backfill dates included.
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    global: Arc<Semaphore>,
+    sources: Arc<HashMap<String, Arc<Semaphore>>>,
+    shutdown: CancellationToken,
+}
+
+impl Scheduler {
+    /// Cancelling `shutdown` stops every unit that is waiting or running.
+    pub fn new(config: &ConcurrencyConfig, shutdown: CancellationToken) -> Self {
+        let permits =
+            |limit: usize| Arc::new(Semaphore::new(limit.clamp(1, Semaphore::MAX_PERMITS)));
+
+        Scheduler {
+            global: permits(config.max_units.unwrap_or(Semaphore::MAX_PERMITS)),
+            sources: Arc::new(
+                config
+                    .sources
+                    .iter()
+                    .map(|(source, limit)| (source.clone(), permits(*limit)))
+                    .collect(),
+            ),
+            shutdown,
+        }
+    }
+
+
+    /// Spawns one unit of work of `source`. The unit waits for a free slot before it starts,
+    /// its time limit and duration only count from there. A unit that times out or is
+    /// cancelled is dropped where it stands, `on_abort` then cleans up after it.
+    pub fn spawn<F, A>(&self, source: &str, limit: Duration, on_abort: A, unit: F) -> UnitHandle
+    where
+        F: Future<Output = Result<UnitStats, JobError>> + Send + 'static,
+        A: FnOnce() + Send + 'static,
+    {
+        let global = self.global.clone();
+        let per_source = self.sources.get(source).cloned();
+        let shutdown = self.shutdown.clone();
+
+        tokio::spawn(async move {
+            let waiting = Instant::now();
+            let outcome = tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => (Err(JobError::Cancelled), waiting.elapsed()),
+                outcome = async {
+                    // take the source slot first so a saturated source does not hold global slots
+                    let _source_permit = match per_source {
+                        Some(semaphore) => Some(semaphore.acquire_owned().await),
+                        None => None,
+                    };
+                    let _global_permit = global.acquire_owned().await;
+
+                    let started = Instant::now();
+                    let result = timeout(limit, unit)
+                        .await
+                        .unwrap_or(Err(JobError::Timeout(limit)));
+                    (result, started.elapsed())
+                } => outcome,
+            };
+
+            if let (Err(JobError::Timeout(_) | JobError::Cancelled), _) = &outcome {
+                on_abort();
+            }
+            outcome
+        })
+    }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::error::JobError;
+use super::job::UploadObject;
+use super::manifest::ColumnSchema;
+use super::utility;
+
+/// What a successful unit of work wrote.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UnitStats {
+    pub rows: usize,
+    pub bytes: u64,
+    pub s3_key: String,
+    /// Hex encoded SHA-256 of the uploaded object.
+    #[serde(default)]
+    pub sha256: String,
+    #[serde(default)]
+    pub schema: Vec<ColumnSchema>,
+    #[serde(default)]
+    pub destinations: Vec<DestinationReport>,
+    /// Completed by an earlier attempt of a resumed run, not run again.
+    #[serde(skip)]
+    pub resumed: bool,
+}
+
+/// Outcome of the upload of a result file to one storage backend.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DestinationReport {
+    pub backend: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+impl DestinationReport {
+    pub fn new(backend: &str, result: &Result<(), JobError>) -> Self {
+        DestinationReport {
+            backend: backend.to_string(),
+            succeeded: result.is_ok(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+        }
+    }
+}
+
+impl UnitStats {
+    pub fn for_object(
+        object: &UploadObject,
+        rows: usize,
+        schema: Vec<ColumnSchema>,
+    ) -> Result<UnitStats, JobError> {
+        Ok(UnitStats {
+            rows,
+            bytes: std::fs::metadata(&object.local_path)?.len(),
+            s3_key: object.key.clone(),
+            sha256: object.sha256.clone(),
+            schema,
+            destinations: Vec::new(),
+            resumed: false,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitStatus {
+    Succeeded,
+    /// Completed by an earlier attempt of a resumed run.
+    Skipped,
+    Failed,
+}
+
+/// Outcome of one unit of work, e.g. an airtable endpoint/year or an impact campaign/report.
+#[derive(Clone, Debug, Serialize)]
+pub struct UnitReport {
+    pub execution_date: String,
+    pub source: String,
+    pub key: String,
+    pub status: UnitStatus,
+    pub rows: Option<usize>,
+    pub bytes: Option<u64>,
+    pub duration_secs: f64,
+    pub s3_key: Option<String>,
+    pub sha256: Option<String>,
+    /// Listed in the manifest of the source only.
+    #[serde(skip)]
+    pub schema: Vec<ColumnSchema>,
+    pub destinations: Vec<DestinationReport>,
+    pub error: Option<String>,
+}
+
+impl UnitReport {
+    pub fn new(
+        execution_date: &str,
+        source: &str,
+        key: &str,
+        result: Result<UnitStats, JobError>,
+        duration: Duration,
+    ) -> Self {
+        let (status, stats, error) = match result {
+            Ok(stats) if stats.resumed => (UnitStatus::Skipped, Some(stats), None),
+            Ok(stats) => (UnitStatus::Succeeded, Some(stats), None),
+            Err(err) => (UnitStatus::Failed, None, Some(err)),
+        };
+        let destinations = match (&stats, &error) {
+            (Some(stats), _) => stats.destinations.clone(),
+            (None, Some(JobError::Upload(reports))) => reports.clone(),
+            _ => Vec::new(),
+        };
+
+        UnitReport {
+            execution_date: execution_date.to_string(),
+            source: source.to_string(),
+            key: key.to_string(),
+            status,
+            rows: stats.as_ref().map(|stats| stats.rows),
+            bytes: stats.as_ref().map(|stats| stats.bytes),
+            duration_secs: duration.as_secs_f64(),
+            sha256: stats.as_ref().map(|stats| stats.sha256.clone()),
+            schema: stats
+                .as_ref()
+                .map(|stats| stats.schema.clone())
+                .unwrap_or_default(),
+            s3_key: stats.map(|stats| stats.s3_key),
+            destinations,
+            error: error.map(|err| err.to_string()),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status != UnitStatus::Failed
+    }
+}
+
+/// Every unit of work of one invocation, written as json once the run is over.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub units: Vec<UnitReport>,
+}
+
+impl RunSummary {
+    pub fn new(run_id: &str, started_at: DateTime<Local>, units: Vec<UnitReport>) -> Self {
+        let count = |status| units.iter().filter(|unit| unit.status == status).count();
+        let (succeeded, skipped, failed) = (
+            count(UnitStatus::Succeeded),
+            count(UnitStatus::Skipped),
+            count(UnitStatus::Failed),
+        );
+
+        RunSummary {
+            run_id: run_id.to_string(),
+            started_at: started_at.to_rfc3339(),
+            finished_at: Local::now().to_rfc3339(),
+            succeeded,
+            skipped,
+            failed,
+            units,
+        }
+    }
+
+    pub fn write_json(&self, file_name: &str) -> Result<(), JobError> {
+        let file = std::fs::File::create(file_name)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn print_table(&self) {
+        let headers = [
+            "EXECUTION_DATE",
+            "SOURCE",
+            "KEY",
+            "STATUS",
+            "ROWS",
+            "BYTES",
+            "DURATION",
+            "S3_KEY",
+            "DESTINATIONS",
+            "ERROR",
+        ];
+        let rows: Vec<Vec<String>> = self
+            .units
+            .iter()
+            .map(|unit| {
+                vec![
+                    unit.execution_date.clone(),
+                    unit.source.clone(),
+                    unit.key.clone(),
+                    match unit.status {
+                        UnitStatus::Succeeded => "ok",
+                        UnitStatus::Skipped => "skipped",
+                        UnitStatus::Failed => "failed",
+                    }
+                    .to_string(),
+                    unit.rows.map(|rows| rows.to_string()).unwrap_or_default(),
+                    unit.bytes.map(|bytes| bytes.to_string()).unwrap_or_default(),
+                    format!("{:.1}s", unit.duration_secs),
+                    unit.s3_key.clone().unwrap_or_default(),
+                    unit.destinations
+                        .iter()
+                        .map(|destination| {
+                            let status = if destination.succeeded { "ok" } else { "failed" };
+                            format!("{} {}", destination.backend, status)
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    unit.error.clone().unwrap_or_default(),
+                ]
+            })
+            .collect();
+
+        utility::print_table(&headers, &rows);
+    }
+}
@@ -1,17 +1,72 @@
 use serde_json::Value;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::io::Read;
 
-pub fn update_nested_value(
-    nested_map: &mut HashMap<String, Value>,
-    outer_key: &str,
-    inner_key: &str,
-    new_value: Value,
-) {
-    let outer_entry = nested_map
-        .entry(outer_key.to_string())
-        .or_insert(serde_json::json!({}));
+/// Value at the dot separated `path` into `value`, numeric segments index arrays.
+pub fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .filter(|segment| !segment.is_empty())
+        .try_fold(value, |value, segment| match value {
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => value.get(segment),
+        })
+}
+
+/// `template` with `{execution_date}`, `{yyyy}`, `{mm}` and `{dd}` filled in from the
+/// `yyyy-mm-dd` execution date.
+pub fn render_date(template: &str, execution_date: &str) -> String {
+    let mut date_parts = execution_date.splitn(3, '-');
+    [
+        ("{execution_date}", execution_date),
+        ("{yyyy}", date_parts.next().unwrap_or_default()),
+        ("{mm}", date_parts.next().unwrap_or_default()),
+        ("{dd}", date_parts.next().unwrap_or_default()),
+    ]
+    .iter()
+    .fold(template.to_string(), |rendered, (placeholder, value)| {
+        rendered.replace(placeholder, value)
+    })
+}
+
+/// Prints rows as left aligned columns, the last column is left unpadded.
+pub fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |row: &[String]| {
+        let last = row.len().saturating_sub(1);
+        let line: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                if i == last {
+                    cell.clone()
+                } else {
+                    format!("{:<width$}", cell, width = widths[i])
+                }
+            })
+            .collect();
+        println!("{}", line.join("  "));
+    };
+
+    print_row(&headers.iter().map(|header| header.to_string()).collect::<Vec<_>>());
+    rows.iter().for_each(|row| print_row(row));
+}
 
-    if let Some(inner_map) = outer_entry.as_object_mut() {
-        inner_map.insert(inner_key.to_string(), new_value);
+/// Hex encoded SHA-256 of a file.
+pub fn sha256_file(path: &str) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buffer[..read]);
     }
 }
@@ -1,54 +1,510 @@
 mod job;
-use chrono::Local;
-use clap::Parser;
-use job::job::{run_task, Airtable, AtJobDetail, EmarsysBq, Impact};
-use std::env;
-use tokio::try_join;
+use chrono::{Local, NaiveDate};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::future::{join_all, try_join_all};
+use futures::{stream, StreamExt, TryStreamExt};
+use job::config::{
+    check_config, load_config, validate_config, PipelineConfig, StorageKind, ALL_SOURCES,
+};
+use job::checkpoint::{CheckpointStore, RunParams};
+use job::http::HttpClient;
+use job::job::{clean_staging_dir, run_source, RunContext, StoragePlatform, StorageRegistry, UploadObject};
+use job::plan::{self, PlanEntry};
+use job::registry::{SourceRegistry, UnitFilters};
+use job::scheduler::Scheduler;
+use job::summary::{RunSummary, UnitReport};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// Every unit of work failed, or the run could not start at all.
+const EXIT_TOTAL_FAILURE: u8 = 1;
+/// Some units of work failed while others succeeded.
+const EXIT_PARTIAL_FAILURE: u8 = 3;
+/// The run was stopped by SIGINT or SIGTERM.
+const EXIT_INTERRUPTED: u8 = 130;
+/// Stands for `{source}` in the manifest key template the run summary is uploaded under.
+const RUN_SUMMARY_SOURCE: &str = "runs";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit codes: 0 every unit succeeded, 1 every unit failed or the run could not start, 3 some units failed, 130 interrupted by SIGINT/SIGTERM"
+)]
 struct Args {
     #[arg(short, long, help = "pipeline config")]
     pipeline_config: String,
+
+    #[command(subcommand)]
+    command: Command,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the extraction for some sources or for all of them
+    Run {
+        #[command(flatten)]
+        dates: DateArgs,
+        #[arg(
+            long,
+            global = true,
+            default_value_t = 1,
+            help = "number of execution dates extracted at the same time"
+        )]
+        parallelism: usize,
+        #[arg(
+            long,
+            global = true,
+            help = "where the json run summary is written, defaults to run_summary_<run id>.json"
+        )]
+        summary_file: Option<String>,
+        #[arg(long, global = true, help = "print the run summary as a table")]
+        summary_table: bool,
+        #[arg(long, global = true, help = "upload the run summary to the default storage backend, keyed by keys.manifest with runs as its source")]
+        upload_summary: bool,
+        #[arg(
+            long,
+            global = true,
+            value_name = "RUN_ID",
+            help = "resume an earlier run with its execution dates, sources and filters, skipping its completed units of work"
+        )]
+        resume: Option<String>,
+        #[arg(
+            long,
+            global = true,
+            help = "keep the staged files of the run once they are uploaded"
+        )]
+        keep_artifacts: bool,
 
-    let args = Args::parse();
+        #[command(flatten)]
+        sources: SourceArgs,
+    },
+    /// List every unit of work a run would execute without calling any api
+    Plan {
+        #[command(flatten)]
+        dates: DateArgs,
+        #[arg(long, global = true, value_enum, default_value_t = PlanFormat::Table)]
+        format: PlanFormat,
 
-    let current_date = Local::now().date_naive().to_string();
-    env::set_var("PIPELINE_CONFIG", args.pipeline_config);
-    env::set_var("CURRENT_DATE", current_date);
+        #[command(flatten)]
+        sources: SourceArgs,
+    },
+    /// Check the pipeline config and every file it references
+    ValidateConfig,
+}
 
-    let airtable = Airtable {
-        job_details: AtJobDetail::new(),
+#[derive(clap::Args, Debug)]
+struct DateArgs {
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["from", "to"],
+        help = "execution date to extract, defaults to today"
+    )]
+    execution_date: Option<NaiveDate>,
+    #[arg(long, global = true, requires = "to", help = "first execution date of a backfill")]
+    from: Option<NaiveDate>,
+    #[arg(long, global = true, requires = "from", help = "last execution date of a backfill")]
+    to: Option<NaiveDate>,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum PlanFormat {
+    Table,
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+struct SourceArgs {
+    #[arg(help = "source instances to run as named in the config, all or none of them for every instance")]
+    sources: Vec<String>,
+    #[arg(
+        long = "filter",
+        value_name = "KEY=VALUE",
+        value_parser = parse_filter,
+        help = "only run the units of work matching the filter, e.g. endpoint=order_sheet, year=2023, table=<key> or campaign=<key>"
+    )]
+    filters: Vec<(String, String)>,
+    #[arg(long, help = "only run this airtable endpoint, e.g. order_sheet, same as --filter endpoint=")]
+    endpoint: Option<String>,
+    #[arg(long, help = "only run this airtable base year, e.g. 2023, same as --filter year=")]
+    year: Option<String>,
+    #[arg(long, help = "only run this table key, same as --filter table=")]
+    table: Option<String>,
+    #[arg(long, help = "only run this impact campaign key, same as --filter campaign=")]
+    campaign: Option<String>,
+}
+
+fn parse_filter(filter: &str) -> Result<(String, String), String> {
+    match filter.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got {}", filter)),
+    }
+}
+
+impl SourceArgs {
+    /// Names of the selected instances, empty for every instance.
+    fn names(&self) -> Vec<String> {
+        if self.sources.iter().any(|name| name == ALL_SOURCES) {
+            Vec::new()
+        } else {
+            self.sources.clone()
+        }
+    }
+
+    fn filters(&self) -> UnitFilters {
+        let flags = [
+            ("endpoint", &self.endpoint),
+            ("year", &self.year),
+            ("table", &self.table),
+            ("campaign", &self.campaign),
+        ];
+        self.filters
+            .iter()
+            .cloned()
+            .chain(flags.into_iter().filter_map(|(key, value)| {
+                value.clone().map(|value| (key.to_string(), value))
+            }))
+            .collect()
+    }
+}
+
+fn execution_dates(dates: &DateArgs) -> Result<Vec<NaiveDate>, Box<dyn std::error::Error>> {
+    match (dates.from, dates.to) {
+        (Some(from), Some(to)) => {
+            if from > to {
+                return Err(format!("--from {} is after --to {}", from, to).into());
+            }
+            Ok(from.iter_days().take_while(|date| *date <= to).collect())
+        }
+        _ => Ok(vec![dates
+            .execution_date
+            .unwrap_or_else(|| Local::now().date_naive())]),
+    }
+}
+
+/// Parameters of a run from the command line. A resumed run keeps those it was started
+/// with, the command line may repeat them but not change them.
+fn run_params(
+    dates: &DateArgs,
+    sources: &SourceArgs,
+    resumed: Option<(&str, RunParams)>,
+) -> Result<RunParams, Box<dyn std::error::Error>> {
+    let given = RunParams {
+        execution_dates: execution_dates(dates)?
+            .iter()
+            .map(NaiveDate::to_string)
+            .collect(),
+        sources: sources.names(),
+        filters: sources.filters().into_iter().collect(),
     };
-    let emarsys_bq = EmarsysBq {
-        table_name: String::new()
+    let Some((run_id, saved)) = resumed else {
+        return Ok(given);
     };
-    let impact = Impact {
-        execution_date: String::new(),
-        api_url: String::new(),
-        report: String::new(),
-        parameters: String::new(),
-        auth_sid: String::new(),
-        auth_token: String::new(),
-        sub_account_name: String::new(),
-        key: String::new(),
+
+    let sorted = |names: &[String]| {
+        let mut names = names.to_vec();
+        names.sort();
+        names.dedup();
+        names
     };
+    let conflicts = [
+        (
+            "execution dates",
+            dates.execution_date.is_some() || dates.from.is_some(),
+            given.execution_dates != saved.execution_dates,
+            format!("{:?}", saved.execution_dates),
+        ),
+        (
+            "sources",
+            !sources.sources.is_empty(),
+            sorted(&given.sources) != sorted(&saved.sources),
+            format!("{:?}", saved.sources),
+        ),
+        (
+            "filters",
+            !given.filters.is_empty(),
+            given.filters != saved.filters,
+            format!("{:?}", saved.filters),
+        ),
+    ];
+    for (param, is_given, differs, saved) in conflicts {
+        if is_given && differs {
+            return Err(format!(
+                "run {} was started with {} {}, resume it without them or with the same ones",
+                run_id, param, saved
+            )
+            .into());
+        }
+    }
+    Ok(saved)
+}
+
+/// Context of a run that calls no api and uploads nothing, for plan and validate-config.
+fn plan_context(
+    config: &Arc<PipelineConfig>,
+    run_id: &str,
+    date: NaiveDate,
+    scheduler: &Scheduler,
+    http: &HttpClient,
+) -> RunContext {
+    RunContext {
+        config: config.clone(),
+        run_id: run_id.to_string(),
+        execution_date: date.to_string(),
+        scheduler: scheduler.clone(),
+        http: http.clone(),
+        checkpoints: CheckpointStore::in_memory(run_id),
+        storage: StorageRegistry::default(),
+        keep_artifacts: false,
+    }
+}
+
+fn exit_code(results: &[UnitReport]) -> ExitCode {
+    let failed = results.iter().filter(|result| !result.is_success()).count();
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else if failed == results.len() {
+        ExitCode::from(EXIT_TOTAL_FAILURE)
+    } else {
+        ExitCode::from(EXIT_PARTIAL_FAILURE)
+    }
+}
+
+/// Cancels `shutdown` on the first SIGINT or SIGTERM.
+fn watch_signals(shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => {
+                    signal.recv().await;
+                }
+                Err(err) => {
+                    warn!("could not listen for SIGTERM: {}", err);
+                    std::future::pending::<()>().await
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => warn!("received SIGINT, cancelling the run"),
+            _ = terminate => warn!("received SIGTERM, cancelling the run"),
+        }
+        shutdown.cancel();
+    });
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    match execute(args).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(EXIT_TOTAL_FAILURE)
+        }
+    }
+}
+
+async fn execute(args: Args) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let config: Arc<PipelineConfig> = Arc::new(load_config(&args.pipeline_config)?);
+    let shutdown = CancellationToken::new();
+    let scheduler = Scheduler::new(&config.concurrency, shutdown.clone());
+    let http = HttpClient::new(&config.http)?;
+
+    match args.command {
+        Command::Run {
+            dates,
+            parallelism,
+            summary_file,
+            summary_table,
+            upload_summary,
+            resume,
+            keep_artifacts,
+            sources,
+        } => {
+            let problems = check_config(&config);
+            if !problems.is_empty() {
+                problems.iter().for_each(|problem| eprintln!("{}", problem));
+                return Err(format!(
+                    "{} problem(s) found in {}, see validate-config",
+                    problems.len(),
+                    args.pipeline_config
+                )
+                .into());
+            }
+            let (run_id, checkpoints, params) = match resume {
+                Some(run_id) => {
+                    let checkpoints =
+                        CheckpointStore::open(&config.paths.checkpoints, &run_id)?;
+                    let params =
+                        run_params(&dates, &sources, Some((&run_id, checkpoints.params())))?;
+                    (run_id, checkpoints, params)
+                }
+                None => {
+                    let run_id = uuid::Uuid::new_v4().to_string();
+                    let params = run_params(&dates, &sources, None)?;
+                    let checkpoints = CheckpointStore::create(
+                        &config.paths.checkpoints,
+                        &run_id,
+                        params.clone(),
+                    )?;
+                    (run_id, checkpoints, params)
+                }
+            };
+            println!("run id {}", run_id);
+            let names = if params.sources.is_empty() {
+                config.source_instances()?.into_keys().collect()
+            } else {
+                params.sources.clone()
+            };
+            let mut kinds: Vec<StorageKind> = names
+                .iter()
+                .flat_map(|name| config.storage.for_source(name))
+                .collect();
+            if upload_summary {
+                kinds.extend(config.storage.backend.0.iter().cloned());
+            }
+            let storage = StorageRegistry::connect(&config, &kinds).await?;
+            let registry = SourceRegistry::default();
+            let filters: UnitFilters = params.filters.into_iter().collect();
+            let mut runs = Vec::new();
+            for date in params.execution_dates {
+                let context = RunContext {
+                    config: config.clone(),
+                    run_id: run_id.clone(),
+                    execution_date: date,
+                    scheduler: scheduler.clone(),
+                    http: http.clone(),
+                    checkpoints: checkpoints.clone(),
+                    storage: storage.clone(),
+                    keep_artifacts,
+                };
+                let built = registry.build(&config, &params.sources, &filters, &context)?;
+                runs.push((context, built));
+            }
+            // the summary of a backfill is keyed under its first execution date
+            let summary_context = runs.first().map(|(context, _)| context.clone());
+            let started_at = Local::now();
+            watch_signals(shutdown.clone());
+            let results: Vec<Vec<UnitReport>> = stream::iter(runs)
+                .map(|(context, sources)| async move {
+                    join_all(sources.iter().map(|source| run_source(source.as_ref(), &context)))
+                        .await
+                        .concat()
+                })
+                .buffer_unordered(parallelism.max(1))
+                .collect()
+                .await;
+            let results: Vec<UnitReport> = results.into_iter().flatten().collect();
+            if !keep_artifacts {
+                clean_staging_dir(&config, &run_id);
+            }
+
+            for result in results.iter().filter(|result| !result.is_success()) {
+                if let Some(e) = &result.error {
+                    eprintln!("{} {} failed: {}", result.source, result.key, e);
+                }
+            }
+
+            let code = if shutdown.is_cancelled() {
+                ExitCode::from(EXIT_INTERRUPTED)
+            } else {
+                exit_code(&results)
+            };
+            let summary = RunSummary::new(&run_id, started_at, results);
+            if summary_table {
+                summary.print_table();
+            }
+            println!(
+                "{} of {} unit(s) of work succeeded",
+                summary.succeeded,
+                summary.units.len()
+            );
+
+            let summary_file =
+                summary_file.unwrap_or_else(|| format!("run_summary_{}.json", run_id));
+            summary.write_json(&summary_file)?;
+            println!("run summary written to {}", summary_file);
+            if let (true, false, Some(context)) =
+                (upload_summary, shutdown.is_cancelled(), summary_context)
+            {
+                let file_name = Path::new(&summary_file)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(&summary_file);
+                let key = context.manifest_key(RUN_SUMMARY_SOURCE, file_name);
+                let object = UploadObject::new(&summary_file, &key, "application/json", None).await?;
+                StoragePlatform::upload_all(
+                    storage.get(&config.storage.backend.0)?,
+                    &object,
+                    config.storage.policy,
+                )
+                .await?;
+            }
 
-    let result = try_join!(
-        run_task(&airtable),
-        run_task(&emarsys_bq),
-        run_task(&impact)
-    );
+            return Ok(code);
+        }
+        Command::Plan {
+            dates,
+            format,
+            sources,
+        } => {
+            let run_id = uuid::Uuid::new_v4().to_string();
+            let registry = SourceRegistry::default();
+            let filters = sources.filters();
+            let mut plans = Vec::new();
+            for date in execution_dates(&dates)? {
+                let context = plan_context(&config, &run_id, date, &scheduler, &http);
+                plans.push(registry.build(&config, &sources.names(), &filters, &context)?);
+            }
+            let entries: Vec<Vec<PlanEntry>> = stream::iter(plans)
+                .then(|sources| async move {
+                    try_join_all(sources.iter().map(|source| source.plan()))
+                        .await
+                        .map(|entries| entries.concat())
+                })
+                .try_collect()
+                .await?;
+            let entries = entries.concat();
 
-    match result {
-        Ok(_) => println!("All tasks completed successfully"),
-        Err(e) => eprintln!("Error in one of the tasks: {}", e),
+            match format {
+                PlanFormat::Table => plan::print_table(&entries),
+                PlanFormat::Json => plan::print_json(&entries)?,
+            }
+        }
+        Command::ValidateConfig => {
+            let mut problems = validate_config(&config).await;
+            let run_id = uuid::Uuid::new_v4().to_string();
+            let context =
+                plan_context(&config, &run_id, Local::now().date_naive(), &scheduler, &http);
+            problems.extend(SourceRegistry::default().validate(&config, &context).await);
+            if !problems.is_empty() {
+                problems.iter().for_each(|problem| eprintln!("{}", problem));
+                return Err(format!(
+                    "{} problem(s) found in {}",
+                    problems.len(),
+                    args.pipeline_config
+                )
+                .into());
+            }
+            println!("{} is valid", args.pipeline_config);
+        }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }